@@ -0,0 +1,30 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use raycast::prelude::*;
+
+fn render(c: &mut Criterion) {
+    let mut map: Map = Map::load("examples/res/demo.level").unwrap();
+    let view: Viewport = Viewport::new(800, 800);
+    let mut out_img: Framebuffer = view.framebuffer();
+    let ray: Ray = map.player_start.unwrap();
+
+    c.bench_function("render 800x800", |b| b.iter(|| {
+        raycast::render(&map, map.spawns.iter(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+    }));
+
+    // Run with `--features parallel`, the name records the thread count the result depends on
+    #[cfg(feature = "parallel")]
+    c.bench_function(&format!("render_parallel 800x800, {} threads", rayon::current_num_threads()), |b| b.iter(|| {
+        raycast::render_parallel(&map, map.spawns.iter(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+    }));
+
+    // Floors and ceilings cover most of the screen looking across the open hall
+    let ray: Ray = Ray::new(glam::Vec2::new(525., 275.), 0.);
+    map.floor_tex(Surface::Texture(Framebuffer::load_png("examples/res/floor.png").unwrap()));
+    map.ceil_tex(Surface::Texture(Framebuffer::load_png("examples/res/ceiling.png").unwrap()));
+    c.bench_function("render 800x800 textured floors, fog", |b| b.iter(|| {
+        raycast::render(&map, map.spawns.iter(), ray, Fog::linear(0., 600.), &|| 0., 0., &view, &mut out_img);
+    }));
+}
+
+criterion_group!(benches, render);
+criterion_main!(benches);
@@ -1,40 +1,27 @@
 use raycast::prelude as rc;
 use macroquad::prelude as mq;
 use glam::Vec2;
-use std::collections::HashMap;
 
 #[macroquad::main(window_conf)]
 async fn main() {
-    raycast::util::set_scrw_scrh(800, 800);
-
-    let mut textures: HashMap<char, mq::Image> = HashMap::new();
-    textures.insert('0', mq::Image::from_file_with_format(include_bytes!("res/wall.png"), Some(mq::ImageFormat::Png)).unwrap());
-    textures.insert('1', mq::Image::from_file_with_format(include_bytes!("res/wall.png"), Some(mq::ImageFormat::Png)).unwrap());
-    textures.insert('2', mq::Image::from_file_with_format(include_bytes!("res/wall.png"), Some(mq::ImageFormat::Png)).unwrap());
-    textures.insert('3', mq::Image::from_file_with_format(include_bytes!("res/wall.png"), Some(mq::ImageFormat::Png)).unwrap());
-    textures.insert('e', mq::Image::from_file_with_format(include_bytes!("res/shrek.png"), Some(mq::ImageFormat::Png)).unwrap());
-
-    let mut map: rc::Map = rc::Map::from_bytes(include_bytes!("res/map"), textures);
-    // map.floor_tex(rc::Surface::Texture(mq::Image::from_file_with_format(include_bytes!("res/floor.png"), Some(mq::ImageFormat::Png)).unwrap()));
-    // map.ceil_tex(rc::Surface::Texture(mq::Image::from_file_with_format(include_bytes!("res/ceiling.png"), Some(mq::ImageFormat::Png)).unwrap()));
-    map.floor_tex(rc::Surface::Color(mq::BEIGE.into()));
-    map.ceil_tex(rc::Surface::Color(mq::GRAY.into()));
-    map.wall_height('0', 2.);
-    // map.wall_height('1', 2.);
-    // map.wall_height('2', 3.);
-    // map.wall_height('3', 4.);
-
-    let mut entities: Vec<rc::Entity> = map.filter_entities(&['e'], &[(20., 30.)]);
+    // Cycled with R, rendering at a fraction of the window and scaling up
+    let render_scales: [f32; 3] = [1., 0.5, 0.25];
+    let mut render_scale: usize = 0;
+    let mut view: rc::Viewport = rc::Viewport::centered(800, 800);
+
+    let mut map: rc::Map = rc::Map::load(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/res/demo.level"))
+        .unwrap_or_else(|e| panic!("{}", e));
+    let mut entities: Vec<rc::Entity> = map.spawns.clone();
 
     let mut items: Vec<rc::Item> = vec![
-        rc::Item::new("gun", include_bytes!("res/gun.png")),
-        rc::Item::new("knife", include_bytes!("res/knife.png")),
+        rc::Item::new("gun", include_bytes!("res/gun.png"), &view),
+        rc::Item::new("knife", include_bytes!("res/knife.png"), &view),
     ];
     let mut selected_index: usize = 0;
 
     let shooting_gun: mq::Texture2D = mq::Texture2D::from_file_with_format(include_bytes!("res/gun-shoot.png"), Some(mq::ImageFormat::Png));
 
-    let mut cam: rc::Ray = rc::Ray::new(Vec2::new(110., 160.), 0.3);
+    let mut cam: rc::Ray = map.player_start.unwrap_or(rc::Ray::new(Vec2::new(110., 160.), 0.3));
 
     let mut prev_mpos: (f32, f32) = mq::mouse_position();
 
@@ -45,12 +32,11 @@ async fn main() {
     let mut last_fps_update: f64 = mq::get_time();
     let mut fps: i32 = mq::get_fps();
 
-    let mut out_img: mq::Image = mq::Image::gen_image_color(
-        rc::scrw() as u16,
-        rc::scrh() as u16,
-        mq::BLACK
-    );
-    let out_tex: mq::Texture2D = mq::Texture2D::from_image(&out_img);
+    let mut out_img: rc::Framebuffer = view.framebuffer();
+    let mut out_tex: mq::Texture2D = out_img.to_texture();
+
+    let mut explored: rc::Explored = rc::Explored::new(&map);
+    let mut rotate_minimap: bool = false;
 
     loop {
         if mq::is_key_pressed(mq::KeyCode::Tab) {
@@ -59,6 +45,20 @@ async fn main() {
             mq::show_mouse(!grabbed);
         }
 
+        if mq::is_key_pressed(mq::KeyCode::R) {
+            render_scale = (render_scale + 1) % render_scales.len();
+            view = rc::Viewport::centered(800, 800).downscaled(render_scales[render_scale]);
+            out_img = view.framebuffer();
+            out_tex = out_img.to_texture();
+            // Items are placed in rendered pixels
+            let name: String = items[selected_index].name.clone();
+            raycast::equip_item(&mut items, &name, &view);
+        }
+
+        if mq::is_key_pressed(mq::KeyCode::M) {
+            rotate_minimap = !rotate_minimap;
+        }
+
         // Controls
         raycast::util::fps_camera_controls(&map, &mut cam, 2.);
         raycast::util::fps_camera_rotation(&mut cam, &mut prev_mpos, 1.);
@@ -72,40 +72,60 @@ async fn main() {
         if mq::is_mouse_button_pressed(mq::MouseButton::Left) {
             // Animations
             match selected_index {
-                0 => items[selected_index].texswap(&shooting_gun, 0.1),
-                1 => items[selected_index].jab(Vec2::new(0., -20.), 0.05),
+                0 => items[selected_index].texswap(&shooting_gun, 0.1, &view),
+                1 => items[selected_index].jab(Vec2::new(0., -20.), 0.05, &view),
                 _ => ()
             }
 
-            // Raycast
-            let ins: rc::Intersection = raycast::cast_ray(&map, entities.iter(), &[], cam);
-            match ins.itype {
-                rc::IntersectionType::Entity { index, .. } => println!("Hit entity {}", index),
-                _ => println!("Hit wall")
+            // Raycast, at the mouse when it is free
+            let (mx, my) = mq::mouse_position();
+            let ray: rc::Ray = if grabbed { cam } else { view.pick_ray(cam, Vec2::new(mx, my)).unwrap_or(cam) };
+            let hit: rc::Hit = raycast::hit_scan(&map, entities.iter(), &[], ray, mq::get_time());
+            match hit.ins.itype {
+                rc::IntersectionType::Entity { index, .. } => println!("Hit entity {} at {:?}", index, hit.texel.unwrap()),
+                rc::IntersectionType::Floor { gpos } => println!("Hit floor of {} at height {}", gpos, hit.pos.z),
+                rc::IntersectionType::Ceiling { gpos } => println!("Hit ceiling of {} at height {}", gpos, hit.pos.z),
+                _ => println!("Hit wall at height {}", hit.pos.z),
+            }
+        }
+
+        // Open doors in front of the player
+        map.update_doors(mq::get_frame_time());
+        if mq::is_key_pressed(mq::KeyCode::E) {
+            let ins: rc::Intersection = map.cast_ray(cam);
+            if let rc::IntersectionType::Wall { gpos, .. } = ins.itype {
+                if ins.distance < 100. {
+                    map.toggle_door(gpos);
+                }
             }
         }
 
         // Equip item
         if mq::is_key_pressed(mq::KeyCode::Key1) {
             selected_index = 0;
-            raycast::equip_item(&mut items, "gun");
+            raycast::equip_item(&mut items, "gun", &view);
         }
 
         if mq::is_key_pressed(mq::KeyCode::Key2) {
             selected_index = 1;
-            raycast::equip_item(&mut items, "knife");
+            raycast::equip_item(&mut items, "knife", &view);
         }
 
         mq::clear_background(mq::BLACK);
-        out_img.bytes.fill(0);
+        out_img.clear();
         // let all_ents: Vec<&rc::Entity> = entities.iter().collect();
-        raycast::render(&map, entities.iter(), cam, rc::Fog::None, &|| 0., &mut out_img);
-        out_tex.update(&out_img);
+        let columns: Vec<(rc::Intersection, f32)> = raycast::render(&map, entities.iter(), cam, map.fog, &|| 0., mq::get_time(), &view, &mut out_img);
+
+        // Minimap in the top right corner, a quarter of the view wide
+        explored.reveal(&map, cam, &columns);
+        let size: usize = view.w as usize / 4;
+        let minimap: rc::Minimap = rc::Minimap { rotate: rotate_minimap, ..rc::Minimap::new(view.w as usize - size - 4, 4, size, size).with_zoom(size as f32 / 600.) };
+        minimap.draw(&map, entities.iter(), cam, &columns, Some(&explored), &mut out_img);
+        out_img.update_texture(&out_tex);
 
-        let (top_x, top_y) = rc::scr_topleft();
-        mq::draw_texture(&out_tex, top_x, top_y, mq::WHITE);
+        view.draw(&out_tex);
 
-        raycast::render_item(&mut items);
+        raycast::render_item(&mut items, &view);
 
         if mq::get_time() - last_fps_update > 0.5 {
             fps = mq::get_fps();
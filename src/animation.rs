@@ -0,0 +1,78 @@
+/// What an animation does once it has played through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    /// Starts over
+    Loop,
+    /// Stays on the last frame
+    Once,
+    /// Plays backwards to the start, then forwards again
+    PingPong,
+}
+
+/// Texture tiles shown one after another, each for its own duration in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    pub frames: Vec<(char, f32)>,
+    pub mode: LoopMode,
+}
+
+impl Animation {
+    pub fn new(frames: Vec<(char, f32)>, mode: LoopMode) -> Self {
+        Self { frames, mode }
+    }
+
+    /// Every frame shown for `duration` seconds
+    pub fn uniform(frames: &[char], duration: f32, mode: LoopMode) -> Self {
+        Self::new(frames.iter().map(|&f| (f, duration)).collect(), mode)
+    }
+
+    /// Seconds to play through once
+    pub fn duration(&self) -> f32 {
+        self.frames.iter().map(|f| f.1).sum()
+    }
+
+    /// Tile shown `time` seconds after the animation started, panics without frames
+    pub fn frame(&self, time: f64) -> char {
+        let total: f64 = self.duration() as f64;
+        if total <= 0. {
+            return self.frames[0].0;
+        }
+
+        let t: f64 = match self.mode {
+            LoopMode::Loop => time.rem_euclid(total),
+            LoopMode::Once => time.clamp(0., total),
+            LoopMode::PingPong => {
+                let t: f64 = time.rem_euclid(2. * total);
+                if t < total { t } else { 2. * total - t }
+            }
+        };
+
+        let mut end: f64 = 0.;
+        for &(tile, duration) in &self.frames {
+            end += duration as f64;
+            if t < end {
+                return tile;
+            }
+        }
+        self.frames[self.frames.len() - 1].0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames() {
+        let frames: Vec<(char, f32)> = vec![('a', 0.5), ('b', 0.25), ('c', 0.25)];
+        let anim: Animation = Animation::new(frames.clone(), LoopMode::Loop);
+        assert_eq!([0., 0.6, 0.8, 1.1, -0.1].map(|t| anim.frame(t)), ['a', 'b', 'c', 'a', 'c']);
+
+        let anim: Animation = Animation::new(frames.clone(), LoopMode::Once);
+        assert_eq!([0.6, 5., -1.].map(|t| anim.frame(t)), ['b', 'c', 'a']);
+
+        // Back through the frames after reaching the end
+        let anim: Animation = Animation::new(frames, LoopMode::PingPong);
+        assert_eq!([0.8, 1.1, 1.3, 1.6, 2.1].map(|t| anim.frame(t)), ['c', 'c', 'b', 'a', 'a']);
+    }
+}
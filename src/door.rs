@@ -0,0 +1,64 @@
+use crate::wall::Axis;
+
+/// Door is passable once open at least this much
+pub const DOOR_PASSABLE: f32 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorState {
+    Closed,
+    Opening,
+    Open,
+    Closing,
+}
+
+/// Thin wall through the middle of its cell that slides sideways into the neighbouring wall
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Door {
+    pub axis: Axis,
+    /// 0 is closed, 1 is fully open
+    pub open: f32,
+    /// Open fraction per second
+    pub speed: f32,
+    target: f32,
+}
+
+impl Door {
+    pub fn new(axis: Axis, speed: f32) -> Self {
+        Self { axis, open: 0., speed, target: 0. }
+    }
+
+    pub fn open(&mut self) {
+        self.target = 1.;
+    }
+
+    pub fn close(&mut self) {
+        self.target = 0.;
+    }
+
+    pub fn toggle(&mut self) {
+        self.target = 1. - self.target;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        let step: f32 = self.speed * dt;
+        self.open = if self.open < self.target {
+            (self.open + step).min(self.target)
+        } else {
+            (self.open - step).max(self.target)
+        };
+    }
+
+    pub fn state(&self) -> DoorState {
+        if self.open == self.target {
+            if self.target > 0. { DoorState::Open } else { DoorState::Closed }
+        } else if self.target > self.open {
+            DoorState::Opening
+        } else {
+            DoorState::Closing
+        }
+    }
+
+    pub fn passable(&self) -> bool {
+        self.open >= DOOR_PASSABLE
+    }
+}
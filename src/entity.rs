@@ -1,18 +1,87 @@
 use crate::util::{self, Ray, Intersection, IntersectionType};
+use crate::map::Map;
 use glam::Vec2;
 use std::f32::consts::PI;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Entity {
     pub pos: Vec2,
+    /// Texture tile, or the tile of a sprite set registered with `Map::sprite_set`
     pub texture: char,
     pub w: f32,
-    pub h: f32
+    pub h: f32,
+    /// Direction the entity faces, picks the frame of its sprite set
+    pub angle: f32,
+    /// Elevation of the sprite's bottom above the floor of its cell, to float or hang from the ceiling
+    pub z: f32,
+}
+
+/// Frames of an entity seen from evenly spaced directions around it, starting from straight in front
+/// and going round the way angles increase
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteSet {
+    pub frames: Vec<char>,
+    /// `frames` only go from the front round to the back, the other side shows them flipped
+    pub mirror: bool,
+}
+
+impl SpriteSet {
+    pub fn new(frames: Vec<char>) -> Self {
+        Self { frames, mirror: false }
+    }
+
+    /// Front to back frames of one side, 5 frames give 8 directions
+    pub fn mirrored(frames: Vec<char>) -> Self {
+        Self { frames, mirror: true }
+    }
+
+    /// Number of view directions
+    pub fn directions(&self) -> usize {
+        if self.mirror {
+            (2 * self.frames.len()).saturating_sub(2).max(1)
+        } else {
+            self.frames.len()
+        }
+    }
+
+    /// Frame seen from `angle` around the entity relative to its facing, and whether it is flipped
+    pub fn frame(&self, angle: f32) -> (char, bool) {
+        let directions: usize = self.directions();
+        let i: usize = (angle.rem_euclid(2. * PI) / (2. * PI / directions as f32)).round() as usize % directions;
+        if i < self.frames.len() {
+            (self.frames[i], false)
+        } else {
+            (self.frames[directions - i], true)
+        }
+    }
 }
 
 impl Entity {
     pub fn new(pos: Vec2, texture: char, size: (f32, f32)) -> Self {
-        Self { pos, texture, w: size.0, h: size.1 }
+        Self { pos, texture, w: size.0, h: size.1, angle: 0., z: 0. }
+    }
+
+    pub fn with_angle(mut self, angle: f32) -> Self {
+        self.angle = angle;
+        self
+    }
+
+    pub fn with_z(mut self, z: f32) -> Self {
+        self.z = z;
+        self
+    }
+
+    /// World elevation of the sprite's bottom edge, its top is `h` higher
+    pub fn bottom(&self, map: &Map) -> f32 {
+        map.floor_height(map.gpos(self.pos)) + self.z
+    }
+
+    /// Texture tile showing the entity to a viewer at `viewer`, and whether it is flipped
+    pub fn sprite(&self, map: &Map, viewer: Vec2) -> (char, bool) {
+        match map.sprite_sets.get(&self.texture) {
+            Some(set) => set.frame(f32::atan2(viewer.y - self.pos.y, viewer.x - self.pos.x) - self.angle),
+            None => (self.texture, false),
+        }
     }
 
     pub fn intersect(&self, ray: Ray) -> Option<Intersection> {
@@ -43,3 +112,19 @@ impl Entity {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprite_frames() {
+        let set: SpriteSet = SpriteSet::new(vec!['a', 'b', 'c', 'd']);
+        assert_eq!((set.frame(0.), set.frame(PI / 2.), set.frame(-PI / 2. + 0.3), set.frame(PI + 0.7)), (('a', false), ('b', false), ('d', false), ('c', false)));
+
+        // Eight directions from five frames, the far side flipped
+        let set: SpriteSet = SpriteSet::mirrored(vec!['1', '2', '3', '4', '5']);
+        assert_eq!(set.directions(), 8);
+        assert_eq!((set.frame(PI / 4.), set.frame(PI), set.frame(-PI / 4.), set.frame(-PI / 2.)), (('2', false), ('5', false), ('2', true), ('3', true)));
+    }
+}
@@ -0,0 +1,111 @@
+/// How fog thickens with distance past `Fog::start`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Falloff {
+    /// Clear air
+    None,
+    /// Evenly up to `Fog::end`
+    Linear,
+    /// `1 - e^(-density * d)`
+    Exp(f32),
+    /// `1 - e^(-(density * d)^2)`, clear close by and thick soon after
+    Exp2(f32),
+}
+
+/// Distance fog blending everything towards `color`, walls, floors, ceilings and sprites alike
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Fog {
+    pub falloff: Falloff,
+    pub color: [u8; 3],
+    /// Distance the fog starts at
+    pub start: f32,
+    /// Distance past which everything is fully fogged
+    pub end: f32,
+    /// Screen radius in pixels past which the image fades into the fog, flashlight style
+    pub vignette: Option<f32>,
+}
+
+impl Fog {
+    pub const NONE: Fog = Fog { falloff: Falloff::None, color: [0, 0, 0], start: 0., end: f32::INFINITY, vignette: None };
+
+    pub fn linear(start: f32, end: f32) -> Self {
+        Self { falloff: Falloff::Linear, start, end, ..Self::NONE }
+    }
+
+    pub fn exp(density: f32) -> Self {
+        Self { falloff: Falloff::Exp(density), ..Self::NONE }
+    }
+
+    pub fn exp2(density: f32) -> Self {
+        Self { falloff: Falloff::Exp2(density), ..Self::NONE }
+    }
+
+    pub fn with_range(mut self, start: f32, end: f32) -> Self {
+        self.start = start;
+        self.end = end;
+        self
+    }
+
+    pub fn with_color(mut self, color: [u8; 3]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_vignette(mut self, radius: f32) -> Self {
+        self.vignette = Some(radius);
+        self
+    }
+
+    /// Share of the fog colour at `distance`, 0 is clear
+    pub fn amount(&self, distance: f32) -> f32 {
+        if matches!(self.falloff, Falloff::None) || distance <= self.start {
+            return 0.;
+        }
+        if distance >= self.end {
+            return 1.;
+        }
+
+        let d: f32 = distance - self.start;
+        match self.falloff {
+            Falloff::None => 0.,
+            Falloff::Linear => f32::min(d / (self.end - self.start), 1.),
+            Falloff::Exp(density) => 1. - f32::exp(-density * d),
+            Falloff::Exp2(density) => 1. - f32::exp(-(density * d).powi(2)),
+        }
+    }
+
+    /// `color` seen through `amount` of fog, alpha is kept
+    pub fn blend(&self, color: [u8; 4], amount: f32) -> [u8; 4] {
+        if amount <= 0. {
+            return color;
+        }
+
+        let mix = |c: u8, f: u8| (c as f32 * (1. - amount) + f as f32 * amount) as u8;
+        [mix(color[0], self.color[0]), mix(color[1], self.color[1]), mix(color[2], self.color[2]), color[3]]
+    }
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falloff() {
+        let fog: Fog = Fog::linear(100., 300.).with_color([200, 100, 0]);
+        assert_eq!((fog.amount(50.), fog.amount(200.), fog.amount(400.)), (0., 0.5, 1.));
+        assert_eq!(fog.blend([0, 100, 200, 128], 0.5), [100, 100, 100, 128]);
+
+        // Exponential fog never quite covers everything before `end`
+        let fog: Fog = Fog::exp(0.01);
+        assert!(fog.amount(100.) > 0.6 && fog.amount(100.) < 0.65);
+        assert_eq!(fog.amount(f32::INFINITY), 1.);
+        assert!(Fog::exp2(0.01).amount(50.) < fog.amount(50.));
+        assert_eq!(Fog::exp2(0.01).with_range(100., 200.).amount(200.), 1.);
+        assert_eq!(Fog::NONE.amount(1000.), 0.);
+    }
+}
@@ -0,0 +1,207 @@
+#[cfg(feature = "macroquad")]
+use macroquad::prelude as mq;
+use std::fs;
+use std::path::Path;
+
+/// RGBA8 pixel buffer owned by the crate, used both as render target and texture storage
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    data: Vec<[u8; 4]>,
+    /// Per-pixel depth filled in by `render`, see `with_depth`
+    depth: Option<Vec<f32>>,
+}
+
+impl Framebuffer {
+    /// Transparent black buffer
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::from_color(width, height, [0, 0, 0, 0])
+    }
+
+    pub fn from_color(width: usize, height: usize, color: [u8; 4]) -> Self {
+        Self { width, height, data: vec![color; width * height], depth: None }
+    }
+
+    /// Panics if `data` doesn't hold exactly `width * height` pixels
+    pub fn from_pixels(width: usize, height: usize, data: Vec<[u8; 4]>) -> Self {
+        assert_eq!(data.len(), width * height, "pixel count doesn't match framebuffer dimensions");
+        Self { width, height, data, depth: None }
+    }
+
+    /// Adds a depth buffer, `render` then stores the distance along the view direction of every pixel it draws.
+    /// Walls, floors, ceilings and sprites all write it, the sky and undrawn pixels are infinitely far.
+    pub fn with_depth(mut self) -> Self {
+        self.depth = Some(vec![f32::INFINITY; self.width * self.height]);
+        self
+    }
+
+    /// Tightly packed RGBA8 bytes, row-major
+    pub fn from_rgba8(width: usize, height: usize, bytes: &[u8]) -> Self {
+        let data: Vec<[u8; 4]> = bytes.chunks_exact(4)
+            .map(|c| [c[0], c[1], c[2], c[3]])
+            .collect();
+        Self::from_pixels(width, height, data)
+    }
+
+    /// Decodes any PNG colour type to RGBA8
+    pub fn from_png(bytes: &[u8]) -> Result<Self, png::DecodingError> {
+        let mut decoder = png::Decoder::new(bytes);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf: Vec<u8> = vec![0; reader.output_buffer_size()];
+        let info: png::OutputInfo = reader.next_frame(&mut buf)?;
+        buf.truncate(info.buffer_size());
+
+        let data: Vec<[u8; 4]> = match info.color_type {
+            png::ColorType::Rgba => buf.chunks_exact(4).map(|c| [c[0], c[1], c[2], c[3]]).collect(),
+            png::ColorType::Rgb => buf.chunks_exact(3).map(|c| [c[0], c[1], c[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks_exact(2).map(|c| [c[0], c[0], c[0], c[1]]).collect(),
+            png::ColorType::Grayscale => buf.iter().map(|&c| [c, c, c, 255]).collect(),
+            // Expanded to rgb(a) by `normalize_to_color8`
+            png::ColorType::Indexed => unreachable!(),
+        };
+
+        Ok(Self::from_pixels(info.width as usize, info.height as usize, data))
+    }
+
+    pub fn load_png(path: impl AsRef<Path>) -> Result<Self, png::DecodingError> {
+        Self::from_png(&fs::read(path)?)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn data(&self) -> &[[u8; 4]] {
+        &self.data
+    }
+
+    pub fn data_mut(&mut self) -> &mut [[u8; 4]] {
+        &mut self.data
+    }
+
+    /// Row-major depth of every pixel, `None` unless the buffer was made `with_depth`
+    pub fn depth(&self) -> Option<&[f32]> {
+        self.depth.as_deref()
+    }
+
+    pub fn depth_at(&self, x: usize, y: usize) -> Option<f32> {
+        self.depth.as_ref().map(|d| d[y * self.width + x])
+    }
+
+    /// Does nothing without a depth buffer
+    pub(crate) fn set_depth(&mut self, x: usize, y: usize, depth: f32) {
+        if let Some(d) = &mut self.depth {
+            d[y * self.width + x] = depth;
+        }
+    }
+
+    /// Pixels and depth borrowed together, for writing both in one pass
+    pub(crate) fn data_and_depth_mut(&mut self) -> (&mut [[u8; 4]], Option<&mut [f32]>) {
+        (&mut self.data, self.depth.as_deref_mut())
+    }
+
+    /// Pushes every pixel back to infinity
+    pub fn clear_depth(&mut self) {
+        if let Some(d) = &mut self.depth {
+            d.fill(f32::INFINITY);
+        }
+    }
+
+    /// Row-major RGBA8 bytes, ready for upload to a GPU texture
+    pub fn as_bytes(&self) -> &[u8] {
+        self.data.as_flattened()
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> [u8; 4] {
+        self.data[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: [u8; 4]) {
+        self.data[y * self.width + x] = color;
+    }
+
+    /// Copy of the `w` by `h` region at (x, y), must lie inside the buffer. Depth is cropped along with the pixels.
+    pub fn crop(&self, x: usize, y: usize, w: usize, h: usize) -> Self {
+        assert!(x + w <= self.width && y + h <= self.height, "crop region out of bounds");
+        let rows = |row: usize| row * self.width + x..row * self.width + x + w;
+        let data: Vec<[u8; 4]> = (y..y + h).flat_map(|row| &self.data[rows(row)]).copied().collect();
+        let depth: Option<Vec<f32>> = self.depth.as_ref().map(|d| (y..y + h).flat_map(|row| &d[rows(row)]).copied().collect());
+        Self { depth, ..Self::from_pixels(w, h, data) }
+    }
+
+    /// Copies `src` over the region at (x, y), which must lie inside the buffer.
+    /// Depth is copied when both buffers have it.
+    pub fn blit(&mut self, src: &Framebuffer, x: usize, y: usize) {
+        assert!(x + src.width <= self.width && y + src.height <= self.height, "blit region out of bounds");
+        for row in 0..src.height {
+            let start: usize = (y + row) * self.width + x;
+            let src_row = row * src.width..(row + 1) * src.width;
+            self.data[start..start + src.width].copy_from_slice(&src.data[src_row.clone()]);
+            if let (Some(depth), Some(src_depth)) = (&mut self.depth, &src.depth) {
+                depth[start..start + src.width].copy_from_slice(&src_depth[src_row]);
+            }
+        }
+    }
+
+    /// Nearest neighbour resize to `w` by `h`, for upscaling a view rendered at a lower resolution without a GPU
+    pub fn resized(&self, w: usize, h: usize) -> Self {
+        let source = |i: usize| (i % w) * self.width / w + (i / w) * self.height / h * self.width;
+        let data: Vec<[u8; 4]> = (0..w * h).map(|i| self.data[source(i)]).collect();
+        let depth: Option<Vec<f32>> = self.depth.as_ref().map(|d| (0..w * h).map(|i| d[source(i)]).collect());
+        Self { depth, ..Self::from_pixels(w, h, data) }
+    }
+
+    pub fn fill(&mut self, color: [u8; 4]) {
+        self.data.fill(color);
+    }
+
+    pub fn clear(&mut self) {
+        self.fill([0, 0, 0, 0]);
+    }
+}
+
+#[cfg(feature = "macroquad")]
+impl From<&mq::Image> for Framebuffer {
+    fn from(img: &mq::Image) -> Self {
+        Self::from_rgba8(img.width(), img.height(), &img.bytes)
+    }
+}
+
+#[cfg(feature = "macroquad")]
+impl From<mq::Image> for Framebuffer {
+    fn from(img: mq::Image) -> Self {
+        Self::from(&img)
+    }
+}
+
+#[cfg(feature = "macroquad")]
+impl From<&Framebuffer> for mq::Image {
+    fn from(fb: &Framebuffer) -> Self {
+        mq::Image {
+            bytes: fb.as_bytes().to_vec(),
+            width: fb.width() as u16,
+            height: fb.height() as u16,
+        }
+    }
+}
+
+#[cfg(feature = "macroquad")]
+impl Framebuffer {
+    /// Nearest filtered, so a view drawn scaled up keeps sharp pixels
+    pub fn to_texture(&self) -> mq::Texture2D {
+        let texture: mq::Texture2D = mq::Texture2D::from_rgba8(self.width as u16, self.height as u16, self.as_bytes());
+        texture.set_filter(mq::FilterMode::Nearest);
+        texture
+    }
+
+    /// Texture must have the same dimensions as the framebuffer
+    pub fn update_texture(&self, texture: &mq::Texture2D) {
+        texture.update_from_bytes(self.width as u32, self.height as u32, self.as_bytes());
+    }
+}
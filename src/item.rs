@@ -1,4 +1,4 @@
-use crate::util;
+use crate::util::Viewport;
 use macroquad::prelude as mq;
 use glam::Vec2;
 
@@ -19,10 +19,29 @@ pub struct Item {
     animation_start: f64,
 }
 
+/// Size in rendered pixels of a texture drawn at its own size on screen
+fn view_size(texture_size: Vec2, view: &Viewport) -> Vec2 {
+    texture_size / view.scale
+}
+
+/// Rendered pixel position of an item resting in the bottom right corner, `raised` into view or just below it
+fn rest_pos(texture_size: Vec2, raised: bool, view: &Viewport) -> Vec2 {
+    let size: Vec2 = view_size(texture_size, view);
+    Vec2::new(view.w as f32 - size.x, view.h as f32 - if raised { size.y } else { 0. })
+}
+
+/// Screen rect of an item at `pos`, cut off at the bottom of the view, and the share of the texture height it shows
+fn screen_rect(texture_size: Vec2, pos: Vec2, view: &Viewport) -> (mq::Rect, f32) {
+    let size: Vec2 = view_size(texture_size, view);
+    let visible: f32 = (view.h as f32 - pos.y).clamp(0., size.y);
+    let screen: Vec2 = view.to_screen(pos);
+    (mq::Rect::new(screen.x, screen.y, size.x * view.scale, visible * view.scale), visible / size.y)
+}
+
 impl Item {
-    pub fn new(name: &str, bytes: &[u8]) -> Self {
+    pub fn new(name: &str, bytes: &[u8], view: &Viewport) -> Self {
         let texture: mq::Texture2D = mq::Texture2D::from_file_with_format(bytes, Some(mq::ImageFormat::Png));
-        let pos: Vec2 = Vec2::new(util::scrw() as f32 - texture.width(), util::scrh() as f32);
+        let pos: Vec2 = rest_pos(Vec2::new(texture.width(), texture.height()), false, view);
         Self {
             name: String::from(name),
             texture,
@@ -32,31 +51,35 @@ impl Item {
         }
     }
 
-    pub fn unequip(&mut self) {
-        self.end_animation();
-        self.animation = Animation::EaseIn { target: Vec2::new(util::scrw() as f32 - self.texture.width(), util::scrh() as f32) };
+    fn size(&self) -> Vec2 {
+        Vec2::new(self.texture.width(), self.texture.height())
+    }
+
+    pub fn unequip(&mut self, view: &Viewport) {
+        self.end_animation(view);
+        self.animation = Animation::EaseIn { target: rest_pos(self.size(), false, view) };
         self.animation_start = mq::get_time();
     }
 
-    pub fn equip(&mut self) {
-        self.end_animation();
-        self.animation = Animation::EaseIn { target: Vec2::new(util::scrw() as f32 - self.texture.width(), util::scrh() as f32 - self.texture.height()) };
+    pub fn equip(&mut self, view: &Viewport) {
+        self.end_animation(view);
+        self.animation = Animation::EaseIn { target: rest_pos(self.size(), true, view) };
         self.animation_start = mq::get_time();
     }
 
-    pub fn jab(&mut self, diff: Vec2, t: f32) {
-        self.end_animation();
+    pub fn jab(&mut self, diff: Vec2, t: f32, view: &Viewport) {
+        self.end_animation(view);
         self.animation = Animation::Jab { diff, t };
         self.animation_start = mq::get_time();
     }
 
-    fn end_jab(&mut self) {
+    fn end_jab(&mut self, view: &Viewport) {
         self.animation = Animation::None;
-        self.equip();
+        self.equip(view);
     }
 
-    pub fn texswap(&mut self, texture: &mq::Texture2D, t: f32) {
-        self.end_animation();
+    pub fn texswap(&mut self, texture: &mq::Texture2D, t: f32, view: &Viewport) {
+        self.end_animation(view);
         self.animation = Animation::TextureSwap { orig: self.texture.clone(), t };
         self.texture = texture.clone();
         self.animation_start = mq::get_time();
@@ -68,16 +91,16 @@ impl Item {
         }
     }
 
-    fn end_animation(&mut self) {
+    fn end_animation(&mut self, view: &Viewport) {
         match self.animation {
             Animation::None => (),
-            Animation::Jab {..} => self.end_jab(),
+            Animation::Jab {..} => self.end_jab(view),
             Animation::EaseIn { target } => self.pos = target,
             Animation::TextureSwap {..} => self.end_texswap()
         }
     }
 
-    pub fn update(&mut self) {
+    pub fn update(&mut self, view: &Viewport) {
         match self.animation {
             Animation::None => (),
             Animation::EaseIn { target } => self.pos += (target - self.pos) / 5.,
@@ -86,7 +109,7 @@ impl Item {
                 if elapsed < t as f64 {
                     self.pos += diff;
                 } else {
-                    self.end_jab();
+                    self.end_jab(view);
                 }
             },
             Animation::TextureSwap { t, .. } => {
@@ -98,19 +121,33 @@ impl Item {
         }
     }
 
-    pub fn render(&self) {
-        let ysection_below_screen: f32 = (self.pos.y + self.texture.height()) - util::scrh() as f32;
-        let new_h: f32 = if ysection_below_screen <= 0. {
-            self.texture.height()
-        } else {
-            self.texture.height() - ysection_below_screen
-        };
-
-        let topleft: (f32, f32) = util::scr_topleft();
-        mq::draw_texture_ex(&self.texture, self.pos.x + topleft.0, self.pos.y + topleft.1, mq::WHITE, mq::DrawTextureParams {
-            dest_size: Some(mq::Vec2::new(self.texture.width(), new_h)),
-            source: Some(mq::Rect::new(0., 0., self.texture.width(), new_h)),
+    /// Positioned in rendered pixels like the view under it, at the texture's own size on screen
+    pub fn render(&self, view: &Viewport) {
+        let (rect, shown) = screen_rect(self.size(), self.pos, view);
+        mq::draw_texture_ex(&self.texture, rect.x, rect.y, mq::WHITE, mq::DrawTextureParams {
+            dest_size: Some(mq::Vec2::new(rect.w, rect.h)),
+            source: Some(mq::Rect::new(0., 0., self.texture.width(), self.texture.height() * shown)),
             ..Default::default()
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn screen_rect_scaled() {
+        // A quarter resolution view upscaled 4 times onto an 800x800 screen
+        let view: Viewport = Viewport::new(800, 800).downscaled(0.25);
+        let gun: Vec2 = Vec2::new(350., 200.);
+
+        let (rect, shown) = screen_rect(gun, rest_pos(gun, true, &view), &view);
+        assert_eq!((rect.x, rect.y, rect.w, rect.h, shown), (450., 600., 350., 200., 1.));
+
+        // Half lowered below the view, only the top half shows
+        let (rect, shown) = screen_rect(gun, rest_pos(gun, true, &view) + Vec2::new(0., 25.), &view);
+        assert_eq!((rect.y, rect.h, shown), (700., 100., 0.5));
+        assert_eq!(screen_rect(gun, rest_pos(gun, false, &view), &view).0.h, 0.);
+    }
+}
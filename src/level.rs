@@ -0,0 +1,735 @@
+use crate::map::{self, Map, MapError, Location, Surface, TextureRef, CellHeights, Sky};
+use crate::framebuffer::Framebuffer;
+use crate::entity::{Entity, SpriteSet};
+use crate::util::Ray;
+use crate::wall::{Axis, ThinWall, Segment};
+use crate::light::Light;
+use crate::fog::{Fog, Falloff};
+use crate::animation::{Animation, LoopMode};
+use glam::{Vec2, IVec2};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// One declaration line of a level file, split on whitespace
+struct Decl<'a> {
+    keyword: &'a str,
+    args: Vec<&'a str>,
+    at: Location,
+}
+
+/// Explicitly placed entity, size defaults to its `entity` declaration
+struct Spawn<'a> {
+    decl: Decl<'a>,
+    tile: char,
+    pos: Vec2,
+    size: Option<(f32, f32)>,
+    angle: f32,
+    z: f32,
+}
+
+impl<'a> Decl<'a> {
+    fn error(&self, message: String) -> MapError {
+        MapError::Syntax { at: self.at.clone(), message }
+    }
+
+    fn expect_args(&self, min: usize, max: usize, usage: &str) -> Result<(), MapError> {
+        if (min..=max).contains(&self.args.len()) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{} {}`", self.keyword, usage)))
+        }
+    }
+
+    fn tile(&self, i: usize) -> Result<char, MapError> {
+        let mut chars = self.args[i].chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii() => Ok(c),
+            _ => Err(self.error(format!("tile `{}` must be a single ascii character", self.args[i]))),
+        }
+    }
+
+    fn num<T: FromStr>(&self, i: usize) -> Result<T, MapError> {
+        self.args[i].parse().map_err(|_| self.error(format!("invalid number `{}`", self.args[i])))
+    }
+}
+
+impl Map {
+    /// Loads a level file: declarations one per line, then `grid` followed by the layout.
+    ///
+    /// ```text
+    /// # comment
+    /// tsize 50
+    /// texture 0 wall.png          # tile texture, relative to the level file
+    /// height 0 2                  # wall height multiplier
+    /// door d 1.5                  # sliding door tile [open speed per second]
+    /// thin f x 0.5                # thin wall tile, plane along x or y [offset within the cell]
+    /// segment 0 50 50 100 100     # free wall textured with a tile, from x1 y1 to x2 y2
+    /// transparent g               # rays pass through the tile, drawn over what is behind it
+    /// cell 2 1 0.25 0.8           # grid x y floor [ceiling] elevation in tiles, for steps and low ceilings
+    /// surface g texture grass.png # floor and ceiling layer tile, same arguments as floor and ceil
+    /// sky sky.png 0.5             # panorama seen through `sky` surfaces [share of the fog it takes]
+    /// light 300 200 255 180 90 200 1.5 shadows # x y r g b radius intensity [shadows]
+    /// ambient 0.3                 # light level of every cell, or `ambient x y level` for one cell
+    /// floors                      # floor layer laid out like the grid, up to the next blank line
+    /// ....
+    /// .gg.
+    /// ....
+    ///
+    /// floor color 238 224 201     # rgb or rgba
+    /// ceil texture ceiling.png    # or `sky` for open sky
+    /// player 110 160 0.3          # x y angle
+    /// entity e 20 30              # tile w h, matching grid tiles become spawns
+    /// spawn e 300 300 20 30 1.5   # tile x y [w h [facing angle [elevation]]], size defaults to the entity declaration
+    /// sprites e mirror a b c d f  # frames of `e` seen from around it, front first [mirror: front to back, flipped on the other side]
+    /// animation w loop a 0.2 b 0.1 # tile shown as the frame tiles in turn for their seconds, loop | once | pingpong
+    /// fog exp2 0.004              # none | linear <start> <end> | exp|exp2 <density> [<start> <end>]
+    /// fog color 90 90 110         # colour everything fades into, black by default
+    /// fog vignette 400            # screen radius in pixels past which the image fades into the fog
+    /// grid
+    /// 0000
+    /// 0..0
+    /// 0000
+    /// ```
+    pub fn load(path: &str) -> Result<Self, MapError> {
+        let bytes: Vec<u8> = fs::read(path).map_err(|source| MapError::Io { path: path.to_string(), source })?;
+        let src: &str = std::str::from_utf8(&bytes).map_err(|source| MapError::Utf8 { file: Some(path.to_string()), source })?;
+        let dir: &Path = Path::new(path).parent().unwrap_or(Path::new(""));
+        Map::parse_level(src, Some(path), dir)
+    }
+
+    /// Level source with texture paths resolved relative to `dir`
+    pub fn from_level(src: &str, dir: &str) -> Result<Self, MapError> {
+        Map::parse_level(src, None, Path::new(dir))
+    }
+
+    fn parse_level(src: &str, file: Option<&str>, dir: &Path) -> Result<Self, MapError> {
+        let mut tsize: f32 = 50.;
+        let mut textures: HashMap<char, Framebuffer> = HashMap::new();
+        // Several tiles commonly share one image
+        let mut loaded: HashMap<String, Framebuffer> = HashMap::new();
+        let mut wall_heights: HashMap<char, f32> = HashMap::new();
+        let mut floor_tex: Surface = Surface::Color([0, 0, 0, 255]);
+        let mut ceil_tex: Surface = Surface::Color([0, 0, 0, 255]);
+        let mut player_start: Option<Ray> = None;
+        let mut entity_sizes: Vec<(char, (f32, f32))> = Vec::new();
+        let mut spawns: Vec<Spawn> = Vec::new();
+        let mut fog: Fog = Fog::NONE;
+        let mut texture_paths: HashMap<TextureRef, String> = HashMap::new();
+        let mut door_tiles: Vec<(char, f32)> = Vec::new();
+        let mut thin_walls: HashMap<char, ThinWall> = HashMap::new();
+        let mut segments: Vec<(Decl, Segment)> = Vec::new();
+        let mut sprite_sets: Vec<(char, SpriteSet, Decl)> = Vec::new();
+        let mut animations: Vec<(char, Animation)> = Vec::new();
+        let mut transparent: HashSet<char> = HashSet::new();
+        let mut cell_heights: Vec<(IVec2, CellHeights, Decl)> = Vec::new();
+        let mut surfaces: HashMap<char, Surface> = HashMap::new();
+        let mut sky: Option<Sky> = None;
+        let mut lights: Vec<Light> = Vec::new();
+        let mut ambient: f32 = 1.;
+        let mut cell_ambient: Vec<(IVec2, f32, Decl)> = Vec::new();
+        // (floor layer, first line, rows, declaration)
+        let mut layers: Vec<(bool, usize, String, Decl)> = Vec::new();
+
+        let mut load_texture = |decl: &Decl, path: &str| -> Result<Framebuffer, MapError> {
+            if let Some(fb) = loaded.get(path) {
+                return Ok(fb.clone());
+            }
+
+            let full_path = dir.join(path);
+            let fb: Framebuffer = Framebuffer::load_png(&full_path)
+                .map_err(|source| MapError::Texture { at: decl.at.clone(), path: full_path.display().to_string(), source })?;
+            loaded.insert(path.to_string(), fb.clone());
+            Ok(fb)
+        };
+
+        let mut grid: Option<(usize, String)> = None;
+        let mut lines = src.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            let mut tokens = line.split_whitespace().take_while(|t| !t.starts_with('#'));
+            let Some(keyword) = tokens.next() else { continue };
+            let decl: Decl = Decl {
+                keyword,
+                args: tokens.collect(),
+                at: Location { file: file.map(String::from), line: i + 1, column: 1 },
+            };
+
+            match keyword {
+                "grid" => {
+                    decl.expect_args(0, 0, "")?;
+                    grid = Some((i + 2, String::new()));
+                    break;
+                }
+                "tsize" => {
+                    decl.expect_args(1, 1, "<size>")?;
+                    tsize = decl.num(0)?;
+                    if !tsize.is_finite() || tsize <= 0. {
+                        return Err(decl.error(format!("tile size `{}` must be positive", decl.args[0])));
+                    }
+                }
+                "texture" => {
+                    decl.expect_args(2, 2, "<tile> <path>")?;
+                    let texture: Framebuffer = load_texture(&decl, decl.args[1])?;
+                    textures.insert(decl.tile(0)?, texture);
+                    texture_paths.insert(TextureRef::Tile(decl.tile(0)?), decl.args[1].to_string());
+                }
+                "height" => {
+                    decl.expect_args(2, 2, "<tile> <multiplier>")?;
+                    wall_heights.insert(decl.tile(0)?, decl.num(1)?);
+                }
+                "floor" | "ceil" | "surface" => {
+                    // Layer surfaces name their tile first
+                    let (first, usage): (usize, &str) = if keyword == "surface" {
+                        (1, "<tile> color <r> <g> <b> [a] | <tile> texture <path> | <tile> sky")
+                    } else {
+                        (0, "color <r> <g> <b> [a] | texture <path> | sky")
+                    };
+                    decl.expect_args(first + 1, first + 5, usage)?;
+                    let args: usize = decl.args.len() - first;
+                    let surface: Surface = match decl.args[first] {
+                        "color" if args >= 4 => Surface::Color([
+                            decl.num(first + 1)?,
+                            decl.num(first + 2)?,
+                            decl.num(first + 3)?,
+                            if args == 5 { decl.num(first + 4)? } else { 255 },
+                        ]),
+                        "texture" if args == 2 => Surface::Texture(load_texture(&decl, decl.args[first + 1])?),
+                        "sky" if args == 1 => Surface::Sky,
+                        _ => return Err(decl.error(format!("expected `{} {}`", keyword, usage))),
+                    };
+
+                    let slot: TextureRef = match keyword {
+                        "floor" => TextureRef::Floor,
+                        "ceil" => TextureRef::Ceil,
+                        _ => TextureRef::Surface(decl.tile(0)?),
+                    };
+                    match surface {
+                        Surface::Texture(_) => texture_paths.insert(slot, decl.args[first + 1].to_string()),
+                        Surface::Color(_) | Surface::Sky => texture_paths.remove(&slot),
+                    };
+
+                    match slot {
+                        TextureRef::Floor => floor_tex = surface,
+                        TextureRef::Ceil => ceil_tex = surface,
+                        _ => { surfaces.insert(decl.tile(0)?, surface); }
+                    }
+                }
+                "floors" | "ceilings" => {
+                    decl.expect_args(0, 0, "")?;
+                    // Rows up to the next blank line
+                    let mut rows: String = String::new();
+                    for (_, line) in lines.by_ref().take_while(|(_, line)| !line.trim().is_empty()) {
+                        rows.push_str(line);
+                        rows.push('\n');
+                    }
+                    layers.push((keyword == "floors", i + 2, rows, decl));
+                }
+                "door" => {
+                    decl.expect_args(1, 2, "<tile> [<speed>]")?;
+                    let speed: f32 = if decl.args.len() == 2 { decl.num(1)? } else { 1. };
+                    door_tiles.push((decl.tile(0)?, speed));
+                }
+                "thin" => {
+                    let usage: &str = "<tile> x|y [<offset>]";
+                    decl.expect_args(2, 3, usage)?;
+                    let axis: Axis = match decl.args[1] {
+                        "x" => Axis::X,
+                        "y" => Axis::Y,
+                        _ => return Err(decl.error(format!("expected `thin {}`", usage))),
+                    };
+                    let offset: f32 = if decl.args.len() == 3 { decl.num(2)? } else { 0.5 };
+                    thin_walls.insert(decl.tile(0)?, ThinWall::new(axis, offset));
+                }
+                "segment" => {
+                    decl.expect_args(5, 5, "<tile> <x1> <y1> <x2> <y2>")?;
+                    let a: Vec2 = Vec2::new(decl.num(1)?, decl.num(2)?);
+                    let b: Vec2 = Vec2::new(decl.num(3)?, decl.num(4)?);
+                    let segment: Segment = Segment::new(a, b, decl.tile(0)?);
+                    segments.push((decl, segment));
+                }
+                "transparent" => {
+                    decl.expect_args(1, 1, "<tile>")?;
+                    transparent.insert(decl.tile(0)?);
+                }
+                "cell" => {
+                    decl.expect_args(3, 4, "<x> <y> <floor> [<ceil>]")?;
+                    let ceil: Option<f32> = if decl.args.len() == 4 { Some(decl.num(3)?) } else { None };
+                    let heights: CellHeights = CellHeights { floor: decl.num(2)?, ceil };
+                    cell_heights.push((IVec2::new(decl.num(0)?, decl.num(1)?), heights, decl));
+                }
+                "player" => {
+                    decl.expect_args(3, 3, "<x> <y> <angle>")?;
+                    player_start = Some(Ray::new(Vec2::new(decl.num(0)?, decl.num(1)?), decl.num(2)?));
+                }
+                "entity" => {
+                    decl.expect_args(3, 3, "<tile> <w> <h>")?;
+                    entity_sizes.push((decl.tile(0)?, (decl.num(1)?, decl.num(2)?)));
+                }
+                "spawn" => {
+                    let usage: &str = "<tile> <x> <y> [<w> <h> [<angle> [<z>]]]";
+                    decl.expect_args(3, 7, usage)?;
+                    let (tile, pos) = (decl.tile(0)?, Vec2::new(decl.num(1)?, decl.num(2)?));
+                    let size: Option<(f32, f32)> = match decl.args.len() {
+                        3 => None,
+                        5..=7 => Some((decl.num(3)?, decl.num(4)?)),
+                        _ => return Err(decl.error(format!("expected `spawn {}`", usage))),
+                    };
+                    let angle: f32 = if decl.args.len() >= 6 { decl.num(5)? } else { 0. };
+                    let z: f32 = if decl.args.len() == 7 { decl.num(6)? } else { 0. };
+                    spawns.push(Spawn { decl, tile, pos, size, angle, z });
+                }
+                "sprites" => {
+                    decl.expect_args(2, usize::MAX, "<tile> [mirror] <frame tiles>...")?;
+                    let mirror: bool = decl.args[1] == "mirror";
+                    let first: usize = if mirror { 2 } else { 1 };
+                    let frames: Vec<char> = (first..decl.args.len()).map(|i| decl.tile(i)).collect::<Result<_, _>>()?;
+                    if frames.is_empty() {
+                        return Err(decl.error(String::from("sprite set needs at least one frame")));
+                    }
+                    let set: SpriteSet = if mirror { SpriteSet::mirrored(frames) } else { SpriteSet::new(frames) };
+                    sprite_sets.push((decl.tile(0)?, set, decl));
+                }
+                "animation" => {
+                    let usage: &str = "<tile> <loop|once|pingpong> <frame> <seconds> [<frame> <seconds>]...";
+                    decl.expect_args(4, usize::MAX, usage)?;
+                    if decl.args.len() % 2 == 1 {
+                        return Err(decl.error(format!("expected `animation {}`", usage)));
+                    }
+                    let mode: LoopMode = match decl.args[1] {
+                        "loop" => LoopMode::Loop,
+                        "once" => LoopMode::Once,
+                        "pingpong" => LoopMode::PingPong,
+                        other => return Err(decl.error(format!("unknown loop mode `{}`", other))),
+                    };
+                    let mut frames: Vec<(char, f32)> = Vec::new();
+                    for i in (2..decl.args.len()).step_by(2) {
+                        frames.push((decl.tile(i)?, decl.num(i + 1)?));
+                    }
+                    animations.push((decl.tile(0)?, Animation::new(frames, mode)));
+                }
+                "sky" => {
+                    decl.expect_args(1, 2, "<path> [<fog>]")?;
+                    let fog: f32 = if decl.args.len() == 2 { decl.num(1)? } else { 0. };
+                    sky = Some(Sky::new(load_texture(&decl, decl.args[0])?).with_fog(fog));
+                    texture_paths.insert(TextureRef::Sky, decl.args[0].to_string());
+                }
+                "light" => {
+                    decl.expect_args(7, 8, "<x> <y> <r> <g> <b> <radius> <intensity> [shadows]")?;
+                    let mut light: Light = Light::new(
+                        Vec2::new(decl.num(0)?, decl.num(1)?),
+                        [decl.num(2)?, decl.num(3)?, decl.num(4)?],
+                        decl.num(5)?,
+                        decl.num(6)?,
+                    );
+                    match decl.args.get(7) {
+                        Some(&"shadows") => light = light.with_shadows(),
+                        Some(_) => return Err(decl.error(String::from("expected `shadows`"))),
+                        None => (),
+                    }
+                    lights.push(light);
+                }
+                "ambient" => {
+                    decl.expect_args(1, 3, "<level> | <x> <y> <level>")?;
+                    match decl.args.len() {
+                        1 => ambient = decl.num(0)?,
+                        3 => cell_ambient.push((IVec2::new(decl.num(0)?, decl.num(1)?), decl.num(2)?, decl)),
+                        _ => return Err(decl.error(String::from("expected `ambient <level> | <x> <y> <level>`"))),
+                    }
+                }
+                "fog" => {
+                    decl.expect_args(1, 4, "none | linear <start> <end> | exp|exp2 <density> [<start> <end>] | color <r> <g> <b> | vignette <radius>")?;
+                    // Colour and vignette apply on top of the falloff, in any order
+                    let model: Fog = match (decl.args[0], decl.args.len()) {
+                        ("none", 1) => Fog::NONE,
+                        ("linear", 3) => Fog::linear(decl.num(1)?, decl.num(2)?),
+                        ("exp", 2) => Fog::exp(decl.num(1)?),
+                        ("exp2", 2) => Fog::exp2(decl.num(1)?),
+                        ("exp", 4) => Fog::exp(decl.num(1)?).with_range(decl.num(2)?, decl.num(3)?),
+                        ("exp2", 4) => Fog::exp2(decl.num(1)?).with_range(decl.num(2)?, decl.num(3)?),
+                        ("color", 4) => {
+                            fog.color = [decl.num(1)?, decl.num(2)?, decl.num(3)?];
+                            continue;
+                        }
+                        ("vignette", 2) => {
+                            fog.vignette = Some(decl.num(1)?);
+                            continue;
+                        }
+                        _ => return Err(decl.error(String::from("expected `fog none | linear <start> <end> | exp|exp2 <density> [<start> <end>] | color <r> <g> <b> | vignette <radius>`"))),
+                    };
+                    fog = Fog { color: fog.color, vignette: fog.vignette, ..model };
+                }
+                _ => return Err(decl.error(format!("unknown declaration `{}`", keyword))),
+            }
+        }
+
+        let Some((first_line, mut layout)) = grid else {
+            return Err(MapError::Empty { file: file.map(String::from) });
+        };
+        for (_, line) in lines {
+            layout.push_str(line);
+            layout.push('\n');
+        }
+
+        let mut animated: HashMap<char, Animation> = HashMap::new();
+        for (tile, animation) in animations {
+            map::check_animation(tile, &animation, &textures)?;
+            animated.insert(tile, animation);
+        }
+
+        // Entities placed on the grid may only have a sprite set
+        let mut sets: HashMap<char, SpriteSet> = HashMap::new();
+        for (tile, set, decl) in sprite_sets {
+            if let Some(&frame) = set.frames.iter().find(|f| !textures.contains_key(f)) {
+                return Err(MapError::UnknownTile { at: decl.at, tile: frame });
+            }
+            sets.insert(tile, set);
+        }
+
+        let mut map: Map = Map::parse(&layout, file, first_line, textures, animated, sets)?;
+        map.tsize = tsize;
+        map.wall_heights = wall_heights;
+        map.floor_tex = floor_tex;
+        map.ceil_tex = ceil_tex;
+        map.player_start = player_start;
+        map.fog = fog;
+        map.texture_paths = texture_paths;
+        map.thin_walls = thin_walls;
+        map.transparent = transparent;
+        map.surfaces = surfaces;
+        map.sky = sky;
+        map.lights = lights;
+        map.ambient = ambient;
+        for (gpos, level, decl) in cell_ambient {
+            if map.out_of_bounds(gpos) {
+                return Err(decl.error(format!("cell {} {} is outside the grid", gpos.x, gpos.y)));
+            }
+            map.set_ambient(gpos, level);
+        }
+        for (floor, first_line, rows, decl) in layers {
+            if rows.is_empty() {
+                return Err(decl.error(format!("`{}` needs rows of tiles", decl.keyword)));
+            }
+            let layer: String = map.parse_layer(&rows, file, first_line)?;
+            if floor {
+                map.floor_layer = Some(layer);
+            } else {
+                map.ceil_layer = Some(layer);
+            }
+        }
+        for (gpos, heights, decl) in cell_heights {
+            if map.out_of_bounds(gpos) {
+                return Err(decl.error(format!("cell {} {} is outside the grid", gpos.x, gpos.y)));
+            }
+            map.set_heights(gpos, heights);
+        }
+        for (decl, segment) in segments {
+            if !map.textures.contains_key(&segment.tile) && !map.animations.contains_key(&segment.tile) {
+                return Err(MapError::UnknownTile { at: decl.at, tile: segment.tile });
+            }
+            map.segments.push(segment);
+        }
+        for (tile, speed) in door_tiles {
+            map.door_tile(tile, speed);
+        }
+
+        let (tags, sizes): (Vec<char>, Vec<(f32, f32)>) = entity_sizes.iter().copied().unzip();
+        map.spawns = map.filter_entities(&tags, &sizes);
+        for Spawn { decl, tile, pos, size, angle, z } in spawns {
+            let Some(size) = size.or_else(|| tags.iter().position(|&t| t == tile).map(|i| sizes[i])) else {
+                return Err(decl.error(format!("spawn of undeclared entity `{}` needs a size", tile)));
+            };
+            if !map.textures.contains_key(&tile) && !map.sprite_sets.contains_key(&tile) && !map.animations.contains_key(&tile) {
+                return Err(MapError::UnknownTile { at: decl.at, tile });
+            }
+            map.spawns.push(Entity::new(pos, tile, size).with_angle(angle).with_z(z));
+        }
+
+        Ok(map)
+    }
+
+    /// Level source in the format read by `load`, loading it back gives an identical map.
+    /// Every texture needs a path, either from `load` or `texture_path`.
+    pub fn to_level(&self) -> Result<String, MapError> {
+        let path = |texture: TextureRef| self.texture_paths.get(&texture).ok_or(MapError::UnnamedTexture { texture });
+        let surface = |keyword: &str, surface: &Surface, texture: TextureRef| -> Result<String, MapError> {
+            Ok(match surface {
+                Surface::Color(c) => format!("{} color {} {} {} {}", keyword, c[0], c[1], c[2], c[3]),
+                Surface::Texture(_) => format!("{} texture {}", keyword, path(texture)?),
+                Surface::Sky => format!("{} sky", keyword),
+            })
+        };
+
+        let mut lines: Vec<String> = vec![format!("tsize {}", self.tsize)];
+
+        let mut tiles: Vec<char> = self.textures.keys().copied().collect();
+        tiles.sort();
+        for tile in tiles {
+            lines.push(format!("texture {} {}", tile, path(TextureRef::Tile(tile))?));
+        }
+
+        let mut heights: Vec<(&char, &f32)> = self.wall_heights.iter().collect();
+        heights.sort_by_key(|h| h.0);
+        for (tile, hmul) in heights {
+            lines.push(format!("height {} {}", tile, hmul));
+        }
+
+        let mut doors: Vec<(&char, &f32)> = self.door_tiles.iter().collect();
+        doors.sort_by_key(|d| d.0);
+        for (tile, speed) in doors {
+            lines.push(format!("door {} {}", tile, speed));
+        }
+
+        let mut thin_walls: Vec<(&char, &ThinWall)> = self.thin_walls.iter().collect();
+        thin_walls.sort_by_key(|t| t.0);
+        for (tile, thin) in thin_walls {
+            let axis: &str = match thin.axis { Axis::X => "x", Axis::Y => "y" };
+            lines.push(format!("thin {} {} {}", tile, axis, thin.offset));
+        }
+
+        let mut transparent: Vec<&char> = self.transparent.iter().collect();
+        transparent.sort();
+        for tile in transparent {
+            lines.push(format!("transparent {}", tile));
+        }
+
+        let mut cells: Vec<(&IVec2, &CellHeights)> = self.cell_heights.iter().collect();
+        cells.sort_by_key(|c| (c.0.y, c.0.x));
+        for (gpos, heights) in cells {
+            match heights.ceil {
+                Some(ceil) => lines.push(format!("cell {} {} {} {}", gpos.x, gpos.y, heights.floor, ceil)),
+                None => lines.push(format!("cell {} {} {}", gpos.x, gpos.y, heights.floor)),
+            }
+        }
+
+        for seg in &self.segments {
+            lines.push(format!("segment {} {} {} {} {}", seg.tile, seg.a.x, seg.a.y, seg.b.x, seg.b.y));
+        }
+
+        lines.push(surface("floor", &self.floor_tex, TextureRef::Floor)?);
+        lines.push(surface("ceil", &self.ceil_tex, TextureRef::Ceil)?);
+
+        let mut surfaces: Vec<(&char, &Surface)> = self.surfaces.iter().collect();
+        surfaces.sort_by_key(|s| s.0);
+        for (tile, layer_surface) in surfaces {
+            lines.push(surface(&format!("surface {}", tile), layer_surface, TextureRef::Surface(*tile))?);
+        }
+        for (keyword, layer) in [("floors", &self.floor_layer), ("ceilings", &self.ceil_layer)] {
+            let Some(layer) = layer else { continue };
+            lines.push(keyword.to_string());
+            for row in layer.as_bytes().chunks(self.w as usize) {
+                lines.push(String::from_utf8_lossy(row).into_owned());
+            }
+            lines.push(String::new());
+        }
+
+        if let Some(start) = self.player_start {
+            lines.push(format!("player {} {} {}", start.orig.x, start.orig.y, start.angle));
+        }
+        let mut sprite_sets: Vec<(&char, &SpriteSet)> = self.sprite_sets.iter().collect();
+        sprite_sets.sort_by_key(|s| s.0);
+        for (tile, set) in sprite_sets {
+            let frames: Vec<String> = set.frames.iter().map(|f| f.to_string()).collect();
+            lines.push(format!("sprites {}{} {}", tile, if set.mirror { " mirror" } else { "" }, frames.join(" ")));
+        }
+        let mut animations: Vec<(&char, &Animation)> = self.animations.iter().collect();
+        animations.sort_by_key(|a| a.0);
+        for (tile, animation) in animations {
+            let mode: &str = match animation.mode {
+                LoopMode::Loop => "loop",
+                LoopMode::Once => "once",
+                LoopMode::PingPong => "pingpong",
+            };
+            let frames: Vec<String> = animation.frames.iter().map(|(f, d)| format!("{} {}", f, d)).collect();
+            lines.push(format!("animation {} {} {}", tile, mode, frames.join(" ")));
+        }
+        for e in &self.spawns {
+            let orientation: String = match (e.angle, e.z) {
+                (angle, z) if z != 0. => format!(" {} {}", angle, z),
+                (angle, _) if angle != 0. => format!(" {}", angle),
+                _ => String::new(),
+            };
+            lines.push(format!("spawn {} {} {} {} {}{}", e.texture, e.pos.x, e.pos.y, e.w, e.h, orientation));
+        }
+
+        if let Some(sky) = &self.sky {
+            lines.push(format!("sky {} {}", path(TextureRef::Sky)?, sky.fog));
+        }
+
+        for l in &self.lights {
+            let shadows: &str = if l.shadows { " shadows" } else { "" };
+            lines.push(format!("light {} {} {} {} {} {} {}{}", l.pos.x, l.pos.y, l.color[0], l.color[1], l.color[2], l.radius, l.intensity, shadows));
+        }
+        if self.ambient != 1. {
+            lines.push(format!("ambient {}", self.ambient));
+        }
+        let mut cell_ambient: Vec<(&IVec2, &f32)> = self.cell_ambient.iter().collect();
+        cell_ambient.sort_by_key(|c| (c.0.y, c.0.x));
+        for (gpos, level) in cell_ambient {
+            lines.push(format!("ambient {} {} {}", gpos.x, gpos.y, level));
+        }
+
+        let fog: Fog = self.fog;
+        let range: String = if fog.start != 0. || fog.end != f32::INFINITY { format!(" {} {}", fog.start, fog.end) } else { String::new() };
+        lines.push(match fog.falloff {
+            Falloff::None => String::from("fog none"),
+            Falloff::Linear => format!("fog linear {} {}", fog.start, fog.end),
+            Falloff::Exp(density) => format!("fog exp {}{}", density, range),
+            Falloff::Exp2(density) => format!("fog exp2 {}{}", density, range),
+        });
+        if fog.color != [0, 0, 0] {
+            lines.push(format!("fog color {} {} {}", fog.color[0], fog.color[1], fog.color[2]));
+        }
+        if let Some(radius) = fog.vignette {
+            lines.push(format!("fog vignette {}", radius));
+        }
+
+        lines.push(String::from("grid"));
+        for y in 0..self.h as i32 {
+            lines.push((0..self.w as i32).map(|x| self.at(x, y)).collect());
+        }
+
+        Ok(lines.join("\n") + "\n")
+    }
+
+    /// Writes the map as a level file, see `to_level`
+    pub fn save(&self, path: &str) -> Result<(), MapError> {
+        fs::write(path, self.to_level()?).map_err(|source| MapError::Io { path: path.to_string(), source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVEL: &str = "\
+# test level
+tsize 40
+texture 0 wall.png
+texture e shrek.png
+texture d floor.png
+height 0 2
+door d 2
+texture f wall.png
+thin f y 0.25
+segment f 40 40 80 80
+transparent f
+cell 1 1 0.25 0.8
+cell 2 1 -0.5
+surface g color 0 200 0
+surface t texture floor.png
+surface s sky
+sky wall.png 0.5
+light 60 60 255 200 100 150 1.5 shadows
+light 100 60 0 0 255 80 1
+ambient 0.25
+ambient 3 1 0.75
+floors
+.....
+.gt..
+.....
+
+floor color 10 20 30
+ceil texture ceiling.png
+player 60 60 0.5
+entity e 20 30
+spawn e 100 60 20 30 1.5
+spawn e 60 60 10 10 0 35
+sprites e mirror e d f
+animation w pingpong 0 0.5 f 0.25
+fog color 90 90 110
+fog exp2 0.004 50 600
+fog vignette 400
+
+grid
+00d00
+0..e0
+0000w
+";
+
+    #[test]
+    fn load_level() {
+        let map: Map = Map::from_level(LEVEL, "examples/res").unwrap();
+        assert_eq!((map.w, map.h, map.tsize), (5., 3., 40.));
+        assert_eq!(map.wall_heights[&'0'], 2.);
+        assert!(matches!(map.floor_tex, Surface::Color([10, 20, 30, 255])));
+        assert!(matches!(map.ceil_tex, Surface::Texture(_)));
+        assert_eq!(map.player_start.unwrap().orig, Vec2::new(60., 60.));
+        assert_eq!(map.fog, Fog::exp2(0.004).with_range(50., 600.).with_color([90, 90, 110]).with_vignette(400.));
+
+        // Grid entity is replaced by floor
+        assert_eq!(map.at(3, 1), '.');
+        assert_eq!(map.spawns.len(), 3);
+        assert_eq!(map.spawns[0].pos, Vec2::new(140., 60.));
+        assert_eq!(map.spawns[1].pos, Vec2::new(100., 60.));
+        assert_eq!(map.door(IVec2::new(2, 0)).unwrap().speed, 2.);
+        assert_eq!((map.spawns[1].w, map.spawns[1].h, map.spawns[1].angle), (20., 30., 1.5));
+        assert_eq!((map.spawns[2].angle, map.spawns[2].z), (0., 35.));
+        assert_eq!(map.sprite_sets[&'e'], SpriteSet::mirrored(vec!['e', 'd', 'f']));
+        assert_eq!(map.animations[&'w'], Animation::new(vec![('0', 0.5), ('f', 0.25)], LoopMode::PingPong));
+        assert_eq!(map.at(4, 2), 'w');
+        assert_eq!(map.thin_walls[&'f'], ThinWall::new(Axis::Y, 0.25));
+        assert!(map.transparent.contains(&'f'));
+        assert_eq!(map.heights(IVec2::new(1, 1)), Some(CellHeights { floor: 0.25, ceil: Some(0.8) }));
+        assert_eq!(map.floor_height(IVec2::new(2, 1)), -20.);
+        assert!(matches!(map.floor_at(IVec2::new(1, 1)), Surface::Color([0, 200, 0, 255])));
+        assert!(matches!(map.floor_at(IVec2::new(2, 1)), Surface::Texture(_)));
+        assert!(matches!(map.floor_at(IVec2::new(3, 1)), Surface::Color([10, 20, 30, 255])));
+        assert!(map.ceil_layer.is_none());
+        assert!(matches!(map.surfaces[&'s'], Surface::Sky));
+        assert_eq!(map.sky.as_ref().unwrap().fog, 0.5);
+        assert_eq!(map.lights[0], Light::new(Vec2::new(60., 60.), [255, 200, 100], 150., 1.5).with_shadows());
+        assert!(!map.lights[1].shadows);
+        assert_eq!((map.ambient_at(IVec2::new(1, 1)), map.ambient_at(IVec2::new(3, 1))), (0.25, 0.75));
+        assert_eq!(map.segments, vec![Segment::new(Vec2::new(40., 40.), Vec2::new(80., 80.), 'f')]);
+    }
+
+    #[test]
+    fn level_errors() {
+        let Err(MapError::Syntax { at, .. }) = Map::from_level("tsize 40\nheight 0 high\ngrid\n0\n", "") else { panic!() };
+        assert_eq!(at.line, 2);
+        let Err(MapError::Syntax { at, .. }) = Map::from_level("tsize 0\ngrid\n0\n", "") else { panic!() };
+        assert_eq!(at.line, 1);
+        assert!(matches!(Map::from_level("tsize -50\ngrid\n0\n", ""), Err(MapError::Syntax { .. })));
+
+        let Err(MapError::Ragged { at, .. }) = Map::from_level("texture 0 wall.png\ngrid\n00\n0\n", "examples/res") else { panic!() };
+        assert_eq!(at.line, 4);
+
+        assert!(matches!(Map::from_level("tsize 40\n", ""), Err(MapError::Empty { .. })));
+        let Err(MapError::LayerSize { at, found, .. }) = Map::from_level("surface g color 0 0 0\nfloors\ng\n\ngrid\n..\n", "") else { panic!() };
+        assert_eq!((at.line, found), (3, (1, 1)));
+        let Err(MapError::UnknownTile { tile: 'x', .. }) = Map::from_level("ceilings\nx.\n\ngrid\n..\n", "") else { panic!() };
+        // Same error as `Map::animation`
+        let Err(MapError::Animation { tile: 'w', .. }) = Map::from_level("animation w loop x 0.5\ngrid\n..\n", "") else { panic!() };
+        assert!(matches!(Map::from_level("texture 0 missing.png\ngrid\n0\n", "examples/res"), Err(MapError::Texture { .. })));
+    }
+
+    #[test]
+    fn grid_sprite_sets() {
+        // Placed on the grid with a sprite set and no texture of its own
+        let map: Map = Map::from_level("texture 0 wall.png\ntexture f shrek.png\nentity s 20 30\nsprites s f\ngrid\n000\n0s0\n000\n", "examples/res").unwrap();
+        assert_eq!((map.spawns.len(), map.spawns[0].texture, map.spawns[0].pos), (1, 's', Vec2::new(75., 75.)));
+        assert_eq!(map.at(1, 1), '.');
+
+        let Err(MapError::UnknownTile { tile: 'x', at }) = Map::from_level("texture 0 wall.png\nsprites s x\ngrid\n0s0\n", "examples/res") else { panic!() };
+        assert_eq!(at.line, 2);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut map: Map = Map::from_level(LEVEL, "examples/res").unwrap();
+        map.set(1, 1, '0');
+        map.player_start = Some(Ray::new(Vec2::new(61.25, 60.), 0.1));
+
+        let saved: String = map.to_level().unwrap();
+        let loaded: Map = Map::from_level(&saved, "examples/res").unwrap();
+        assert_eq!(loaded.to_level().unwrap(), saved);
+        assert_eq!(loaded.at(1, 1), '0');
+        assert_eq!(loaded.player_start.unwrap().orig, Vec2::new(61.25, 60.));
+        assert_eq!(loaded.spawns.len(), 3);
+        assert_eq!(loaded.fog, map.fog);
+
+        map.textures.insert('x', Framebuffer::new(1, 1));
+        assert!(matches!(map.to_level(), Err(MapError::UnnamedTexture { texture: TextureRef::Tile('x') })));
+    }
+}
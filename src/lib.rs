@@ -1,51 +1,132 @@
 pub mod util;
 pub mod map;
+mod level;
+#[cfg(feature = "tiled")]
+pub mod tiled;
 pub mod entity;
+pub mod framebuffer;
+pub mod door;
+pub mod wall;
+pub mod light;
+pub mod fog;
+pub mod animation;
+pub mod minimap;
+#[cfg(feature = "macroquad")]
 pub mod item;
 pub mod prelude;
 
-use util::{Ray, Intersection, IntersectionType, Direction};
+use util::{Ray, Intersection, IntersectionType, Hit, Direction, Viewport};
 use entity::Entity;
 use map::{Map, Surface};
+use framebuffer::Framebuffer;
+#[cfg(feature = "macroquad")]
 use item::Item;
+#[cfg(feature = "macroquad")]
 use macroquad::prelude as mq;
-use glam::{Vec2, Vec3, IVec2};
+use glam::{Vec2, IVec2};
 use std::f32::consts::PI;
+use std::ops::Range;
 
-#[derive(Debug, Copy, Clone)]
-pub enum Fog {
-    None,
-    /// distance
-    Point(f32),
-    /// distance, radius
-    Directional(f32, f32),
+pub use fog::Fog;
+
+/// `out_img` must have the dimensions of `view`, `time` in seconds picks the frame of animated textures.
+/// A framebuffer made `with_depth` also gets the depth of every pixel, for depth testing overlays against the scene.
+/// Returns the opaque wall intersection and ray angle of every screen column, for `Minimap::draw` and `Explored::reveal`.
+#[allow(clippy::too_many_arguments)]
+pub fn render<'a, I>(map: &Map, entities: I, ray: Ray, fog: Fog, floor_level: &impl Fn() -> f32, time: f64, view: &Viewport, out_img: &mut Framebuffer) -> Vec<(Intersection, f32)> where I: Iterator<Item = &'a Entity> + Clone {
+    debug_assert_eq!((out_img.width(), out_img.height()), (view.w as usize, view.h as usize));
+    // Screen row at eye level, shared by every pass so walls, floors and sprites line up
+    let horizon: f32 = view.horizon(ray.vangle) + floor_level();
+    out_img.clear_depth();
+    let columns: Vec<(Intersection, f32)> = render_band(map, entities, ray, 0, fog, horizon, time, view, out_img);
+    render_vignette(fog, view, out_img);
+    columns
 }
 
-pub fn render<'a, I>(map: &Map, entities: I, ray: Ray, fog: Fog, floor_level: &impl Fn() -> f32, out_img: &mut mq::Image) where I: Iterator<Item = &'a Entity> + Clone {
-    // let scrdim: IVec2 = IVec2::new(mq::screen_width() as i32, mq::screen_height() as i32);
-    let vins: Vec<(Intersection, f32)> = cast_rays(map, ray);
+/// Same output as `render`, with bands of columns rendered on the rayon thread pool.
+/// With a single thread the bands would only add overhead, so it renders serially.
+#[cfg(feature = "parallel")]
+#[allow(clippy::too_many_arguments)]
+pub fn render_parallel<'a, I>(map: &Map, entities: I, ray: Ray, fog: Fog, floor_level: &impl Fn() -> f32, time: f64, view: &Viewport, out_img: &mut Framebuffer) -> Vec<(Intersection, f32)> where I: Iterator<Item = &'a Entity> + Clone {
+    use rayon::prelude::*;
+    /// Columns per band, narrow enough to balance walls of different cost across threads
+    const BAND: usize = 16;
+
+    if rayon::current_num_threads() == 1 {
+        return render(map, entities, ray, fog, floor_level, time, view, out_img);
+    }
+
+    debug_assert_eq!((out_img.width(), out_img.height()), (view.w as usize, view.h as usize));
+    let horizon: f32 = view.horizon(ray.vangle) + floor_level();
+    let entities: Vec<&Entity> = entities.collect();
+    out_img.clear_depth();
+
+    // Every band starts from its own copy of the output, so blending sees the same pixels as the serial path
+    let img: &Framebuffer = out_img;
+    let starts: Vec<usize> = (0..img.width()).step_by(BAND).collect();
+    let bands: Vec<(Framebuffer, Vec<(Intersection, f32)>)> = starts.par_iter()
+        .map(|&x0| {
+            let mut band: Framebuffer = img.crop(x0, 0, BAND.min(img.width() - x0), img.height());
+            let columns: Vec<(Intersection, f32)> = render_band(map, entities.iter().copied(), ray, x0 as i32, fog, horizon, time, view, &mut band);
+            (band, columns)
+        })
+        .collect();
+    let mut columns: Vec<(Intersection, f32)> = Vec::with_capacity(img.width());
+    for (x0, (band, band_columns)) in starts.into_iter().zip(bands) {
+        out_img.blit(&band, x0, 0);
+        columns.extend(band_columns);
+    }
 
-    for (x, (ins, angle)) in vins.iter().enumerate() {
+    render_vignette(fog, view, out_img);
+    columns
+}
+
+/// Screen columns `x0..x0 + band width` into `band`, which holds just those columns
+#[allow(clippy::too_many_arguments)]
+fn render_band<'a, I>(map: &Map, entities: I, ray: Ray, x0: i32, fog: Fog, horizon: f32, time: f64, view: &Viewport, band: &mut Framebuffer) -> Vec<(Intersection, f32)> where I: Iterator<Item = &'a Entity> + Clone {
+    let vins: Vec<(Intersection, Vec<Intersection>, f32)> = cast_rays(map, ray, view, x0..x0 + band.width() as i32);
+    let eye: f32 = eye_height(map, ray.orig);
+
+    // Walls and steps column by column, then the floors and ceilings between them row by row
+    let mut columns: Vec<Column> = Vec::with_capacity(vins.len());
+    let mut clips: Vec<Vec<Clip>> = Vec::with_capacity(vins.len());
+    for (x, (ins, _, angle)) in vins.iter().enumerate() {
         let mut cast_ray: Ray = Ray::new(ray.orig, *angle);
         cast_ray.vangle = ray.vangle;
+        let mut column: Column = Column { ray: cast_ray, dir: cast_ray.dir(), cos_col: f32::cos(view.column_angle(x0 + x as i32)), planes: Vec::new() };
+
+        clips.push(render_column(map, &mut column, ins, x as i32, eye, fog, horizon, time, view, band));
+        columns.push(column);
+    }
+    render_planes(map, &columns, fog, horizon, view, band);
+
+    for (x, ((ins, layers, _), (column, clips))) in vins.iter().zip(columns.iter().zip(&clips)).enumerate() {
+        let (x, cast_ray, cos_col): (i32, Ray, f32) = (x as i32, column.ray, column.cos_col);
 
-        let wall_res = render_wall(map, ins, cast_ray, x as i32, fog, floor_level, out_img);
-        render_floor_and_ceil_yrange(map, cast_ray, ins, x as i32, wall_res.0, util::scrh(), -1, fog, &map.floor_tex, out_img);
-        render_floor_and_ceil_yrange(map, cast_ray, ins, x as i32, 0, wall_res.1 as i32, 1, fog, &map.ceil_tex, out_img);
-        render_entities(map, cast_ray, x as i32, entities.clone(), ins.distance, fog, floor_level, out_img);
+        // Back to front, sprites between transparent walls are drawn in between them
+        let mut far: f32 = ins.distance;
+        for layer in layers.iter().rev() {
+            let clip: Clip = clip_at(clips, layer.distance);
+            render_entities(map, cast_ray, x, cos_col, entities.clone(), layer.distance, far, clips, eye, fog, horizon, time, view, band);
+            render_wall(map, cast_ray, layer, x, clip.floor, eye, clip, fog, horizon, time, view, band);
+            far = layer.distance;
+        }
+        render_entities(map, cast_ray, x, cos_col, entities.clone(), 0., far, clips, eye, fog, horizon, time, view, band);
     }
 
-    if let Fog::Directional(_, radius) = fog {
+    vins.into_iter().map(|(ins, _, angle)| (ins, angle)).collect()
+}
+
+/// Fades the image into the fog colour away from its centre
+fn render_vignette(fog: Fog, view: &Viewport, out_img: &mut Framebuffer) {
+    if let Some(radius) = fog.vignette {
         let imgdims = (out_img.width(), out_img.height());
-        let out_data: &mut [[u8; 4]] = out_img.get_image_data_mut();
+        let out_data: &mut [[u8; 4]] = out_img.data_mut();
         let mut index: usize = 0;
         for y in 0..imgdims.1 {
             for x in 0..imgdims.0 {
-                let r: f32 = Vec2::new(x as f32, y as f32).distance(Vec2::new(util::scrw() as f32 / 2., util::scrh() as f32 / 2.));
-                let brightness: f32 = (1. - r / radius).max(0.).min(1.);
-
-                let color: [u8; 4] = out_data[index];
-                out_data[index] = [color[0], color[1], color[2], (brightness * color[3] as f32) as u8];
+                let r: f32 = Vec2::new(x as f32, y as f32).distance(Vec2::new(view.w as f32 / 2., view.h as f32 / 2.));
+                out_data[index] = fog.blend(out_data[index], (r / radius).clamp(0., 1.));
                 index += 1;
             }
         }
@@ -53,65 +134,176 @@ pub fn render<'a, I>(map: &Map, entities: I, ray: Ray, fog: Fog, floor_level: &i
 }
 
 /// Ignores entities
-// Returns [(Wall intersection, angle)]
-fn cast_rays(map: &Map, ray: Ray) -> Vec<(Intersection, f32)> {
-    let angle_range: f32 = PI / 3.;
-    let start_angle: f32 = ray.angle - angle_range / 2.;
-
-    let mut res: Vec<(Intersection, f32)> = Vec::new();
-    for i in 0..util::scrw() {
-        let angle: f32 = start_angle + (i as f32 / util::scrw() as f32 * angle_range);
-        let mut ins: Intersection = map.cast_ray(Ray::new(ray.orig, angle));
-        ins.fisheye_distance *= f32::cos(util::restrict_angle(angle - ray.angle));
-        res.push((ins, angle));
+// Returns [(Opaque wall intersection, transparent walls in front of it, angle)] for screen columns `columns`
+fn cast_rays(map: &Map, ray: Ray, view: &Viewport, columns: Range<i32>) -> Vec<(Intersection, Vec<Intersection>, f32)> {
+    let mut res: Vec<(Intersection, Vec<Intersection>, f32)> = Vec::new();
+    for i in columns {
+        // Columns are evenly spaced on the projection plane, not in angle
+        let angle: f32 = ray.angle + view.column_angle(i);
+        let (mut ins, mut layers) = map.cast_ray_layers(Ray::new(ray.orig, angle));
+        let cos: f32 = f32::cos(util::restrict_angle(angle - ray.angle));
+        for ins in std::iter::once(&mut ins).chain(layers.iter_mut()) {
+            ins.fisheye_distance *= cos;
+        }
+        res.push((ins, layers, angle));
     }
 
     res
 }
 
+/// Rows of a column still visible past `distance` along its ray, after the floors, ceilings and steps in front
+#[derive(Debug, Clone, Copy)]
+struct Clip {
+    distance: f32,
+    /// Floor elevation of the cell the ray is in at `distance`
+    floor: f32,
+    top: i32,
+    bottom: i32,
+}
+
+/// Ray of a screen column, with the floor and ceiling spans `render_column` leaves to `render_planes`
+struct Column {
+    ray: Ray,
+    dir: Vec2,
+    /// Cosine of the ray's angle to the view direction
+    cos_col: f32,
+    planes: Vec<PlaneSpan>,
+}
+
+/// Rows `y0..y1` of a column showing a floor (`pitch_direction` -1) or ceiling (1) plane `dist_to_plane` below or above the eye
+#[derive(Debug, Clone, Copy)]
+struct PlaneSpan {
+    y0: i32,
+    y1: i32,
+    pitch_direction: i32,
+    dist_to_plane: f32,
+}
+
+fn clip_at(clips: &[Clip], distance: f32) -> Clip {
+    *clips.iter().rev().find(|c| c.distance <= distance).unwrap_or(&clips[0])
+}
+
+/// Step faces of the cells the ray crosses front to back, then the wall it hits, into column `x` of `out_img`.
+/// Floors and ceilings in between are added to `column.planes`.
+/// Returns where each stretch of the ray is still visible, for the transparent walls and sprites drawn over it.
+#[allow(clippy::too_many_arguments)]
+fn render_column(map: &Map, column: &mut Column, ins: &Intersection, x: i32, eye: f32, fog: Fog, horizon: f32, time: f64, view: &Viewport, out_img: &mut Framebuffer) -> Vec<Clip> {
+    let (ray, cos_col): (Ray, f32) = (column.ray, column.cos_col);
+    // Screen row of elevation `z` at distance `d` along the ray, and the first pixel row centered below a screen row
+    let row = |z: f32, d: f32| -> f32 { horizon + (eye - z) * view.proj_dist() / (d * cos_col) };
+    let below = |r: f32| -> i32 { (r - 0.5).floor() as i32 + 1 };
+
+    let cells: Vec<(IVec2, f32)> = map.traverse(ray, ins.distance);
+    let base: f32 = map.floor_height(cells[cells.len() - 1].0);
+    let (offset, h) = wall_rows(map, ins, base, eye, horizon, view);
+    // Cells without a ceiling height are open up to the top of the wall
+    let wall_top: f32 = map.tsize * map.wall_heights.get(&map.wall_tile(ins)).unwrap_or(&1.);
+
+    let mut clip: Clip = Clip { distance: 0., floor: eye - map.tsize / 2., top: 0, bottom: view.h };
+    let mut clips: Vec<Clip> = vec![clip];
+    for (i, &(gpos, exit)) in cells.iter().enumerate() {
+        let next: Option<IVec2> = cells.get(i + 1).map(|c| c.0);
+        let floor: f32 = map.floor_height(gpos);
+        let ceil: f32 = map.ceil_height(gpos).unwrap_or(wall_top);
+        let next_floor: Option<f32> = next.map(|n| map.floor_height(n));
+        let next_ceil: Option<f32> = next.map(|n| map.ceil_height(n).unwrap_or(wall_top));
+        // Step faces are textured along the grid line they stand on
+        let hit: Vec2 = ray.along(exit);
+        let u: f32 = if next.is_some_and(|n| n.x != gpos.x) { hit.y } else { hit.x };
+
+        // Runs of cells at the same height are drawn as one span, up to where the height changes or the wall
+        if next_floor != Some(floor) {
+            let y: i32 = if next.is_some() { below(row(floor, exit)) } else { offset + h }.clamp(clip.top, clip.bottom);
+            if floor < eye {
+                column.planes.push(PlaneSpan { y0: y, y1: clip.bottom, pitch_direction: -1, dist_to_plane: eye - floor });
+            }
+            clip.bottom = y;
+        }
+
+        // Step faces take the surface of the cell they raise or lower
+        if let Some(next_floor) = next_floor.filter(|&f| f > floor) {
+            let y: i32 = below(row(next_floor, exit)).clamp(clip.top, clip.bottom);
+            render_step_face(map, ray, x, cos_col, y, clip.bottom, u, exit, eye, map.floor_at(next.unwrap()), fog, horizon, view, out_img);
+            clip.bottom = y;
+        }
+
+        if next_ceil != Some(ceil) {
+            let y: i32 = if next.is_some() { below(row(ceil, exit)) } else { offset }.clamp(clip.top, clip.bottom);
+            if ceil > eye {
+                column.planes.push(PlaneSpan { y0: clip.top, y1: y, pitch_direction: 1, dist_to_plane: ceil - eye });
+            }
+            clip.top = y;
+        }
+
+        if let Some(next_ceil) = next_ceil.filter(|&c| c < ceil) {
+            let y: i32 = below(row(next_ceil, exit)).clamp(clip.top, clip.bottom);
+            render_step_face(map, ray, x, cos_col, clip.top, y, u, exit, eye, map.ceil_at(next.unwrap()), fog, horizon, view, out_img);
+            clip.top = y;
+        }
+
+        if let Some(next_floor) = next_floor {
+            clip.distance = exit;
+            clip.floor = next_floor;
+            clips.push(clip);
+        }
+    }
+
+    render_wall(map, ray, ins, x, base, eye, clip, fog, horizon, time, view, out_img);
+    clips
+}
+
+/// (top row, height in rows) of a wall standing on `base`
+fn wall_rows(map: &Map, ins: &Intersection, base: f32, eye: f32, horizon: f32, view: &Viewport) -> (i32, i32) {
+    let hmul: f32 = *map.wall_heights.get(&map.wall_tile(ins)).unwrap_or(&1.);
+
+    // Pixels per world unit at the wall's depth
+    let scale: f32 = view.proj_dist() / ins.fisheye_distance;
+    let bottom: f32 = horizon + (eye - base) * scale;
+    let h: i32 = ((map.tsize * hmul - base) * scale) as i32;
+    (bottom as i32 - h, h)
+}
+
 /// Returns (wall bottom, wall top)
-fn render_wall(map: &Map, ins: &Intersection, ray: Ray, x: i32, fog: Fog, floor_lev: &impl Fn() -> f32, out_img: &mut mq::Image) -> (i32, i32) {
-    let gpos: IVec2 = ins.wall_gpos();
-    let hmul: f32 = *map.wall_heights.get(&map.at(gpos.x, gpos.y)).unwrap_or(&1.);
-
-    let floor_level: f32 = (util::scrh() as f32 / 2.) * (1. + f32::tan(-ray.vangle) / f32::tan(1. / 2.)) + floor_lev();
-    let h: i32 = ((map.tsize * hmul * util::scrh() as f32) / ins.fisheye_distance) as i32;
-    let offset: i32 = floor_level as i32 - (h / 2) - (((hmul - 1.) / (hmul * 2.)) * h as f32) as i32;
-
-    let texture: &mq::Image = map.textures.get(&map.at(ins.wall_gpos().x, ins.wall_gpos().y)).unwrap();
-    let IntersectionType::Wall { face, .. } = ins.itype else { unreachable!() };
-    // Horizontal walls only have endp.x change, vertical walls only have endp.y change
-    // Horizontal walls collide by north and south
-    let endp: Vec2 = ray.along(ins.distance);
-    let (texture_index, shading) = if matches!(face, Direction::South | Direction::North) {
-        (endp.x, 0.8)
-    } else {
-        (endp.y, 1.)
-    };
-    let fog: f32 = if !matches!(fog, Fog::None) {
-        calculate_fog(fog, ins.distance)
-    } else {
-        shading
+#[allow(clippy::too_many_arguments)]
+fn render_wall(map: &Map, ray: Ray, ins: &Intersection, x: i32, base: f32, eye: f32, clip: Clip, fog: Fog, horizon: f32, time: f64, view: &Viewport, out_img: &mut Framebuffer) -> (i32, i32) {
+    let (offset, h) = wall_rows(map, ins, base, eye, horizon, view);
+
+    let texture: &Framebuffer = map.texture(map.wall_tile(ins), time).unwrap();
+    let (col, shading): (f32, f32) = match ins.itype {
+        // Horizontal walls collide by north and south
+        IntersectionType::Wall { face, col, .. } => (col, if matches!(face, Direction::South | Direction::North) { 0.8 } else { 1. }),
+        // Blends between the two grid shades by how much the segment faces along x
+        IntersectionType::Segment { col, normal, .. } => (col, 0.8 + 0.2 * normal.x.abs()),
+        _ => unreachable!(),
     };
+    let fog_amount: f32 = fog.amount(ins.distance);
+    // Lit once per column, just in front of the face so it takes the light of the open cell
+    let light: Option<[f32; 3]> = light_at(map, ray.along((ins.distance - 0.5).max(0.)));
 
-    let srcx: u32 = ((texture_index % map.tsize) / map.tsize * texture.width() as f32) as u32;
+    let srcx: u32 = ((col * texture.width() as f32) as u32).min(texture.width() as u32 - 1);
 
-    let y0: i32 = offset.max(0).min(out_img.height() as i32);
-    let y1: i32 = (offset + h).min(out_img.height() as i32);
+    let y0: i32 = offset.max(clip.top).min(clip.bottom);
+    let y1: i32 = (offset + h).min(clip.bottom);
 
     let mut out_i: usize = y0 as usize * out_img.width() + x as usize;
     let out_di: usize = out_img.width();
-    let out_data: &mut [[u8; 4]] = out_img.get_image_data_mut();
-    let tex_data: &[[u8; 4]] = texture.get_image_data();
+    let (out_data, mut depth) = out_img.data_and_depth_mut();
+    let tex_data: &[[u8; 4]] = texture.data();
 
     for y in y0..y1 {
         let srcy: u32 = (((y - offset) as f32 / h as f32) * texture.height() as f32) as u32;
         let mut color: [u8; 4] = tex_data[srcy as usize * texture.width() + srcx as usize];
         if color[3] > 0 {
-            color[0] = (fog * color[0] as f32) as u8;
-            color[1] = (fog * color[1] as f32) as u8;
-            color[2] = (fog * color[2] as f32) as u8;
-            out_data[out_i] = color;
+            if let Some(depth) = &mut depth {
+                depth[out_i] = ins.fisheye_distance;
+            }
+            color[0] = (shading * color[0] as f32) as u8;
+            color[1] = (shading * color[1] as f32) as u8;
+            color[2] = (shading * color[2] as f32) as u8;
+            if let Some(light) = light {
+                color = lit(color, light);
+            }
+            out_data[out_i] = blend(out_data[out_i], fog.blend(color, fog_amount));
         }
         out_i += out_di;
     }
@@ -119,95 +311,207 @@ fn render_wall(map: &Map, ins: &Intersection, ray: Ray, x: i32, fog: Fog, floor_
     (offset + h, offset)
 }
 
-fn render_floor_and_ceil_yrange(map: &Map, ray: Ray, ins: &Intersection, x: i32, y0: i32, y1: i32, pitch_direction: i32, fog: Fog, surface: &Surface, out_img: &mut mq::Image) {
-    // From wall bottom to screen bottom
-    let y0: i32 = y0.max(0).min(util::scrh());
-    let y1: i32 = y1.max(0).min(util::scrh());
+/// Floor and ceiling spans of every column, drawn row by row to walk the output in memory order.
+/// The distance to a plane is the same along a screen row, each pixel just divides it by its column's `cos_col`.
+fn render_planes(map: &Map, columns: &[Column], fog: Fog, horizon: f32, view: &Viewport, out_img: &mut Framebuffer) {
+    let proj_dist: f32 = view.proj_dist();
+    let sky_fog: f32 = sky_fog(map, fog);
+    let lit: bool = map.is_lit();
+    let width: usize = out_img.width();
+    let (out_data, mut depth) = out_img.data_and_depth_mut();
 
-    let mut out_i: usize = y0 as usize * out_img.width() + x as usize;
-    let out_di: usize = out_img.width();
-    let out_data: &mut [[u8; 4]] = out_img.get_image_data_mut();
-    let surf_data: &[[u8; 4]] = match surface {
-        Surface::Texture(img) => img.get_image_data(),
-        Surface::Color(_) => &[], // Doesn't matter what goes here, won't be used anyways
-    };
+    // Spans by the row they start on, each column shows at most one at a time
+    let mut starts: Vec<(i32, usize, PlaneSpan)> = columns.iter().enumerate()
+        .flat_map(|(x, c)| c.planes.iter().filter(|s| s.y0 < s.y1).map(move |&span| (span.y0, x, span)))
+        .collect();
+    starts.sort_unstable_by_key(|s| s.0);
+    let mut starts = starts.into_iter().peekable();
+    let mut active: Vec<Option<PlaneSpan>> = vec![None; columns.len()];
 
-    for y in y0..y1 {
-        // Find ray angles corresponding to screen pixel
-        let ha: f32 = (x as f32 / util::scrw() as f32) - 0.5;
-        let va: f32 = (y as f32 / util::scrh() as f32) - 0.5;
-        // x = ray.angle, y = angle looking down, z is useless
-        let dir: Vec3 = Vec3::new(ha, f32::sin(va + ray.vangle), 1.).normalize();
-
-        // How many `dir.y` it takes to get to the floor
-        let gpos: IVec2 = ins.wall_gpos();
-        let wall_h: f32 = *map.wall_heights.get(&map.at(gpos.x, gpos.y)).unwrap_or(&1.);
-        let dist_to_wall: f32 = if pitch_direction > 0 {
-            map.tsize * wall_h - map.tsize / 2.
-        } else {
-            map.tsize / 2.
-        };
-        let tvert: f32 = -pitch_direction as f32 * dist_to_wall / dir.y;
-        let new_pos: Vec2 = ray.along(tvert);
-        let distance: f32 = ray.orig.distance(new_pos);
-        let fog: f32 = calculate_fog(fog, distance);
+    for y in 0..view.h {
+        while let Some((_, x, span)) = starts.next_if(|s| s.0 <= y) {
+            active[x] = Some(span);
+        }
+
+        // (pitch direction, plane) of the last row distance worked out on this row
+        let mut row: Option<(i32, f32, f32)> = None;
+        for (x, (column, active)) in columns.iter().zip(active.iter_mut()).enumerate() {
+            let Some(span) = *active else { continue };
+            if y >= span.y1 {
+                *active = None;
+                continue;
+            }
+
+            // Pixels between this row and the horizon, the plane is at `proj_dist / dy` times its height
+            let dy: f32 = -span.pitch_direction as f32 * (y as f32 + 0.5 - horizon);
+            if dy <= 0. {
+                continue;
+            }
+            let row_distance: f32 = match row {
+                Some((pitch_direction, plane, distance)) if pitch_direction == span.pitch_direction && plane == span.dist_to_plane => distance,
+                _ => {
+                    let distance: f32 = span.dist_to_plane * proj_dist / dy;
+                    row = Some((span.pitch_direction, span.dist_to_plane, distance));
+                    distance
+                }
+            };
+
+            let distance: f32 = row_distance / column.cos_col;
+            let new_pos: Vec2 = column.ray.orig + column.dir * distance;
+            let out_i: usize = y as usize * width + x;
 
-        // Rendering
+            // The cell is only looked up when its surface or light can differ from the rest
+            let (layer, default): (&Option<String>, &Surface) = if span.pitch_direction < 0 { (&map.floor_layer, &map.floor_tex) } else { (&map.ceil_layer, &map.ceil_tex) };
+            let surface: &Surface = match layer {
+                Some(_) => if span.pitch_direction < 0 { map.floor_at(map.gpos(new_pos)) } else { map.ceil_at(map.gpos(new_pos)) },
+                None => default,
+            };
+            let color: [u8; 4] = match surface {
+                Surface::Texture(texture) => {
+                    let (tx, ty): (usize, usize) = (wrap(new_pos.x, texture.width()), wrap(new_pos.y, texture.height()));
+                    opaque(texture.data()[ty * texture.width() + tx])
+                }
+                Surface::Color(color) => opaque(*color),
+                // Neither lit nor fogged by distance
+                Surface::Sky => {
+                    out_data[out_i] = fog.blend(sky_color(map, column.ray.angle, y, horizon, view), sky_fog);
+                    continue;
+                }
+            };
+
+            let color: [u8; 4] = if lit { self::lit(color, map.light_at(new_pos)) } else { color };
+            out_data[out_i] = fog.blend(color, fog.amount(distance));
+            if let Some(depth) = &mut depth {
+                depth[out_i] = row_distance;
+            }
+        }
+    }
+}
+
+/// Texel of coordinate `x` on a texture repeating every `size` pixels, the same as `(x % size) as usize` without the fmod call.
+/// In f64 the quotient of two f32s never rounds up to the next integer, and the remainder is exact.
+fn wrap(x: f32, size: usize) -> usize {
+    let (x, size): (f64, f64) = (x as f64, size as f64);
+    (x - (x / size).trunc() * size) as usize
+}
+
+/// Face where the floor or ceiling changes height `distance` along the ray, textured with that surface at (`u`, elevation)
+#[allow(clippy::too_many_arguments)]
+fn render_step_face(map: &Map, ray: Ray, x: i32, cos_col: f32, y0: i32, y1: i32, u: f32, distance: f32, eye: f32, surface: &Surface, fog: Fog, horizon: f32, view: &Viewport, out_img: &mut Framebuffer) {
+    let depth: f32 = distance * cos_col;
+    let sky_fog: f32 = sky_fog(map, fog);
+    let fog_amount: f32 = fog.amount(distance);
+    let light: Option<[f32; 3]> = light_at(map, ray.along((distance - 0.5).max(0.)));
+
+    for y in y0.max(0)..y1.min(view.h) {
+        let z: f32 = eye - (y as f32 + 0.5 - horizon) * depth / view.proj_dist();
         let color: [u8; 4] = match surface {
             Surface::Texture(texture) => {
-                let tc: Vec2 = new_pos % Vec2::new(texture.width() as f32, texture.height() as f32);
-                let mut color: [u8; 4] = surf_data[tc.y as usize * texture.width() + tc.x as usize];
-                color[3] = (fog * 255.) as u8;
-                color
+                let tx: usize = (u.rem_euclid(texture.width() as f32) as usize).min(texture.width() - 1);
+                let ty: usize = (z.rem_euclid(texture.height() as f32) as usize).min(texture.height() - 1);
+                opaque(texture.get(tx, ty))
+            }
+            Surface::Color(color) => opaque(*color),
+            Surface::Sky => {
+                out_img.set(x as usize, y as usize, fog.blend(sky_color(map, ray.angle, y, horizon, view), sky_fog));
+                continue;
             }
-            Surface::Color(color) => [color[0], color[1], color[2], (fog * 255.) as u8],
         };
-
-        out_data[out_i] = color;
-        out_i += out_di;
+        let color: [u8; 4] = match light {
+            Some(light) => lit(color, light),
+            None => color,
+        };
+        out_img.set(x as usize, y as usize, fog.blend(color, fog_amount));
+        out_img.set_depth(x as usize, y as usize, depth);
     }
 }
 
-fn render_entities<'a, I>(map: &Map, ray: Ray, col: i32, entities: I, wall_dist: f32, fog: Fog, floor_lev: &impl Fn() -> f32, out_img: &mut mq::Image) where I: Iterator<Item = &'a Entity> {
+/// Panorama pixel at screen row `y` of a column looking along `angle`, black without a sky
+fn sky_color(map: &Map, angle: f32, y: i32, horizon: f32, view: &Viewport) -> [u8; 4] {
+    let Some(sky) = &map.sky else { return [0, 0, 0, 255] };
+    let texture: &Framebuffer = &sky.texture;
+
+    // Scrolls with the view angle, the bottom row sits on the horizon
+    let texel_angle: f32 = 2. * PI / texture.width() as f32;
+    let tx: usize = (util::restrict_angle(angle) / texel_angle) as usize % texture.width();
+    let elevation: f32 = f32::atan((horizon - y as f32 - 0.5) / view.proj_dist());
+    let ty: f32 = texture.height() as f32 - elevation / texel_angle;
+    texture.get(tx, ty.clamp(0., texture.height() as f32 - 1.) as usize)
+}
+
+/// Fog over the sky, which is infinitely far away and takes the share of the fog its map allows
+fn sky_fog(map: &Map, fog: Fog) -> f32 {
+    map.sky.as_ref().map_or(0., |sky| sky.fog) * fog.amount(f32::INFINITY)
+}
+
+/// Light reaching `pos`, `None` when the map is unlit and colours are left as they are
+fn light_at(map: &Map, pos: Vec2) -> Option<[f32; 3]> {
+    map.is_lit().then(|| map.light_at(pos))
+}
+
+fn lit(color: [u8; 4], light: [f32; 3]) -> [u8; 4] {
+    let channel = |i: usize| (color[i] as f32 * light[i]).min(255.) as u8;
+    [channel(0), channel(1), channel(2), color[3]]
+}
+
+/// Floors, ceilings and steps cover whatever is behind them
+fn opaque(color: [u8; 4]) -> [u8; 4] {
+    [color[0], color[1], color[2], 255]
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render_entities<'a, I>(map: &Map, ray: Ray, col: i32, cos_col: f32, entities: I, near: f32, far: f32, clips: &[Clip], eye: f32, fog: Fog, horizon: f32, time: f64, view: &Viewport, out_img: &mut Framebuffer) where I: Iterator<Item = &'a Entity> {
     let mut vins: Vec<(&Entity, Intersection)> = entities
         .map(|e| (e, e.intersect(ray))) // entity -> (entity, intersection w/ entity)
         .filter(|x| x.1.is_some()) // Remove `None` intersection variants
         .map(|x| (x.0, x.1.unwrap())) // Unwrap all `Some`s
-        .filter(|x| x.1.distance < wall_dist) // Entities in front of wall
+        .filter(|x| x.1.distance >= near && x.1.distance < far) // Entities between the two walls
         .collect();
 
     // Sort in descending, render farther entities first
     vins.sort_by(|a, b| b.1.distance.partial_cmp(&a.1.distance).unwrap());
 
-    let floor_level: f32 = (util::scrh() as f32 / 2.) * (1. + f32::tan(-ray.vangle) / f32::tan(1. / 2.)) + floor_lev();
     for (ent, ins) in &vins {
-        let h: f32 = (ent.h * util::scrh() as f32) / ins.distance;
-        let middle_h: f32 = (map.tsize / 2. * util::scrh() as f32) / ins.distance;
-        let offset: f32 = floor_level + middle_h - h;
+        // Same projection as walls, sprites stand `z` above the floor of their cell
+        let scale: f32 = view.proj_dist() / (ins.distance * cos_col);
+        let h: f32 = ent.h * scale;
+        let offset: f32 = horizon + (eye - ent.bottom(map)) * scale - h;
 
         let h: i32 = h as i32;
         let offset: i32 = offset as i32;
 
-        let srcx: u32 = (ins.entity_col() * map.textures.get(&ent.texture).unwrap().width() as f32) as u32;
-        let texture: &mq::Image = map.textures.get(&ent.texture).unwrap();
+        // Frame of the sprite set facing the camera, then of its animation
+        let (tile, flipped): (char, bool) = ent.sprite(map, ray.orig);
+        let texture: &Framebuffer = map.texture(tile, time).unwrap();
+        let u: f32 = if flipped { 1. - ins.entity_col() } else { ins.entity_col() };
+        let srcx: u32 = ((u * texture.width() as f32) as u32).min(texture.width() as u32 - 1);
 
-        let fog: f32 = calculate_fog(fog, ins.distance);
+        let fog_amount: f32 = fog.amount(ins.distance);
+        let light: Option<[f32; 3]> = light_at(map, ray.along(ins.distance));
 
-        let y0: i32 = offset.max(0);
-        let y1: i32 = (offset + h).min(out_img.height() as i32);
+        // Hidden behind steps in front of the sprite
+        let clip: Clip = clip_at(clips, ins.distance);
+        let y0: i32 = offset.max(clip.top);
+        let y1: i32 = (offset + h).min(clip.bottom);
 
         let mut out_i: usize = y0 as usize * out_img.width() + col as usize;
         let out_di: usize = out_img.width();
-        let out_data: &mut [[u8; 4]] = out_img.get_image_data_mut();
-        let tex_data: &[[u8; 4]] = texture.get_image_data();
+        let (out_data, mut depth) = out_img.data_and_depth_mut();
+        let tex_data: &[[u8; 4]] = texture.data();
 
         for y in y0..y1 {
             let srcy: u32 = (((y - offset) as f32 / h as f32) * texture.height() as f32) as u32;
-            let mut color: [u8; 4] = tex_data[(srcy as usize * texture.width() + srcx as usize).min(tex_data.len() - 1).max(0)];
+            let mut color: [u8; 4] = tex_data[(srcy as usize * texture.width() + srcx as usize).min(tex_data.len() - 1)];
 
             if color[3] > 0 {
-                color[3] = (fog * 255.) as u8;
-                out_data[out_i] = color;
+                color = opaque(color);
+                if let Some(light) = light {
+                    color = lit(color, light);
+                }
+                out_data[out_i] = fog.blend(color, fog_amount);
+                if let Some(depth) = &mut depth {
+                    depth[out_i] = ins.distance * cos_col;
+                }
             }
 
             out_i += out_di;
@@ -215,6 +519,7 @@ fn render_entities<'a, I>(map: &Map, ray: Ray, col: i32, entities: I, wall_dist:
     }
 }
 
+#[cfg(feature = "macroquad")]
 pub fn render_2d(map: &Map, ray: Ray) {
     let w: f32 = mq::screen_width() / map.w;
     let h: f32 = mq::screen_height() / map.h;
@@ -237,6 +542,12 @@ pub fn render_2d(map: &Map, ray: Ray) {
         }
     }
 
+    let sx: f32 = mq::screen_width() / (map.w * map.tsize);
+    let sy: f32 = mq::screen_height() / (map.h * map.tsize);
+    for seg in &map.segments {
+        mq::draw_line(seg.a.x * sx, seg.a.y * sy, seg.b.x * sx, seg.b.y * sy, 2., mq::GRAY);
+    }
+
     let ox: f32 = ray.orig.x * (mq::screen_width() / (map.w * map.tsize));
     let oy: f32 = ray.orig.y * (mq::screen_height() / (map.h * map.tsize));
     mq::draw_rectangle(
@@ -252,32 +563,45 @@ pub fn render_2d(map: &Map, ray: Ray) {
     mq::draw_line(ox, oy, endx, endy, 3., mq::BLUE);
 }
 
-fn calculate_fog(fog: Fog, distance: f32) -> f32 {
-    match fog {
-        Fog::None => 1.,
-        Fog::Point(dist) | Fog::Directional(dist, _) => 1. - f32::min(distance / dist, 1.),
+/// Source over compositing of `src` onto `dst`
+fn blend(dst: [u8; 4], src: [u8; 4]) -> [u8; 4] {
+    if src[3] == 255 {
+        return src;
     }
+
+    let a: f32 = src[3] as f32 / 255.;
+    let mix = |s: u8, d: u8| (s as f32 * a + d as f32 * (1. - a)).round() as u8;
+    [mix(src[0], dst[0]), mix(src[1], dst[1]), mix(src[2], dst[2]), (src[3] as f32 + dst[3] as f32 * (1. - a)).round() as u8]
 }
 
-pub fn render_item(items: &mut Vec<Item>) {
+#[cfg(feature = "macroquad")]
+pub fn render_item(items: &mut Vec<Item>, view: &Viewport) {
     for item in items {
-        item.update();
-        item.render();
+        item.update(view);
+        item.render(view);
     }
 }
 
 /// Animated swap of items
-pub fn equip_item(items: &mut Vec<Item>, item_name: &str) {
+#[cfg(feature = "macroquad")]
+pub fn equip_item(items: &mut Vec<Item>, item_name: &str, view: &Viewport) {
     for item in items {
-        item.unequip();
+        item.unequip(view);
         if item.name == item_name {
-            item.equip();
+            item.equip(view);
         }
     }
 }
 
+/// Eye elevation of a camera at `pos`, `tsize / 2` above the floor it stands on
+fn eye_height(map: &Map, pos: Vec2) -> f32 {
+    map.floor_height(map.gpos(pos)) + map.tsize / 2.
+}
+
+/// First wall or entity along `ray`. Shots leave from eye level, `ray.vangle` tilts them so they can pass over or under entities.
 pub fn cast_ray<'a, I>(map: &Map, entities: I, ignored_ents: &[char], ray: Ray) -> Intersection where I: Iterator<Item = &'a Entity> {
     let map_ins: Intersection = map.cast_ray(ray);
+    let eye: f32 = eye_height(map, ray.orig);
 
     let mut ent_ins: Intersection = Intersection::new(IntersectionType::Entity { index: 0, col: 0. }, f32::INFINITY);
     for (i, ent) in entities.enumerate() {
@@ -286,7 +610,10 @@ pub fn cast_ray<'a, I>(map: &Map, entities: I, ignored_ents: &[char], ray: Ray)
         }
 
         if let Some(ins) = ent.intersect(ray) {
-            if ins.distance < ent_ins.distance {
+            // Height of the shot where it crosses the sprite
+            let z: f32 = eye - ins.distance * f32::tan(ray.vangle);
+            let bottom: f32 = ent.bottom(map);
+            if ins.distance < ent_ins.distance && (bottom..=bottom + ent.h).contains(&z) {
                 ent_ins = ins;
                 if let IntersectionType::Entity { index, .. } = &mut ent_ins.itype {
                     *index = i;
@@ -297,3 +624,502 @@ pub fn cast_ray<'a, I>(map: &Map, entities: I, ignored_ents: &[char], ray: Ray)
 
     if map_ins.distance < ent_ins.distance { map_ins } else { ent_ins }
 }
+
+/// `cast_ray` down to the pixel: entities are only hit where their sprite, as drawn at `time`, is opaque.
+/// Shots aimed over or under the wall stop where they meet the floor or ceiling of the cells on the way,
+/// or at the top of the wall.
+pub fn hit_scan<'a, I>(map: &Map, entities: I, ignored_ents: &[char], ray: Ray, time: f64) -> Hit where I: Iterator<Item = &'a Entity> {
+    let mut map_ins: Intersection = map.cast_ray(ray);
+    let eye: f32 = eye_height(map, ray.orig);
+    // Height of the shot `distance` away
+    let height = |distance: f32| eye - distance * f32::tan(ray.vangle);
+
+    // Cells without a ceiling height are open up to the top of the wall, like `render_column` draws them
+    let wall_top: f32 = map.tsize * map.wall_heights.get(&map.wall_tile(&map_ins)).unwrap_or(&1.);
+    let mut entry: f32 = 0.;
+    for (gpos, exit) in map.traverse(ray, map_ins.distance) {
+        let floor: f32 = map.floor_height(gpos);
+        let ceil: f32 = map.ceil_height(gpos).unwrap_or(wall_top);
+        // Steps up or down stop the shot where it enters the cell
+        let crossing = |z: f32| ((eye - z) / f32::tan(ray.vangle)).max(entry);
+        if height(exit) < floor {
+            map_ins = Intersection::new(IntersectionType::Floor { gpos }, crossing(floor));
+            break;
+        } else if height(exit) > ceil {
+            map_ins = Intersection::new(IntersectionType::Ceiling { gpos }, crossing(ceil));
+            break;
+        }
+        entry = exit;
+    }
+
+    let mut vins: Vec<(usize, &Entity, Intersection)> = entities
+        .enumerate()
+        .filter(|(_, e)| !ignored_ents.contains(&e.texture))
+        .filter_map(|(i, e)| e.intersect(ray).map(|ins| (i, e, ins)))
+        .filter(|x| x.2.distance < map_ins.distance)
+        .collect();
+    vins.sort_by(|a, b| a.2.distance.partial_cmp(&b.2.distance).unwrap());
+
+    for (i, ent, mut ins) in vins {
+        let z: f32 = height(ins.distance);
+        let top: f32 = ent.bottom(map) + ent.h;
+        if z < top - ent.h || z > top {
+            continue;
+        }
+
+        // Same texel lookup as `render_entities`
+        let (tile, flipped): (char, bool) = ent.sprite(map, ray.orig);
+        let texture: &Framebuffer = map.texture(tile, time).unwrap();
+        let u: f32 = if flipped { 1. - ins.entity_col() } else { ins.entity_col() };
+        let texel: (usize, usize) = (
+            ((u * texture.width() as f32) as usize).min(texture.width() - 1),
+            (((top - z) / ent.h * texture.height() as f32) as usize).min(texture.height() - 1),
+        );
+        if texture.get(texel.0, texel.1)[3] == 0 {
+            continue;
+        }
+
+        if let IntersectionType::Entity { index, .. } = &mut ins.itype {
+            *index = i;
+        }
+        return Hit { ins, pos: ray.along(ins.distance).extend(z), texel: Some(texel) };
+    }
+
+    let z: f32 = match map_ins.itype {
+        IntersectionType::Floor { gpos } => map.floor_height(gpos),
+        IntersectionType::Ceiling { gpos } => map.ceil_height(gpos).unwrap_or(wall_top),
+        _ => height(map_ins.distance).min(wall_top),
+    };
+    Hit { ins: map_ins, pos: ray.along(map_ins.distance).extend(z), texel: None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Three by three open cells inside a wall, the layout most tests render
+    pub(crate) const ROOM: &str = "00000\n0...0\n0...0\n0...0\n00000\n";
+
+    /// Map of `layout` with red `'0'` walls, `textures` adds tiles or replaces the walls
+    pub(crate) fn room(layout: &str, textures: Vec<(char, Framebuffer)>) -> Map {
+        let mut all: HashMap<char, Framebuffer> = HashMap::new();
+        all.insert('0', Framebuffer::from_color(4, 4, [255, 0, 0, 255]));
+        all.extend(textures);
+        Map::from(layout, all).unwrap()
+    }
+
+    /// 16 by 16 view and a framebuffer to render it into
+    fn screen() -> (Viewport, Framebuffer) {
+        let view: Viewport = Viewport::new(16, 16);
+        (view, view.framebuffer())
+    }
+
+    #[test]
+    fn render_headless() {
+        let map: Map = room(ROOM, vec![]);
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+
+        assert_eq!(out_img.get(8, 8), [255, 0, 0, 255]);
+        assert_eq!(out_img.get(8, 0), [0, 0, 0, 255]);
+        // Floor and ceiling meet the walls without gaps
+        assert!(out_img.data().iter().all(|c| c[3] == 255));
+    }
+
+    #[test]
+    fn transparent_walls() {
+        let mut map: Map = room("00000\n0...0\n0..g0\n0...0\n00000\n", vec![('g', Framebuffer::from_color(4, 4, [0, 0, 255, 128]))]);
+        map.transparent_tile('g');
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+
+        // Glass blended over the wall behind it
+        assert_eq!(out_img.get(8, 8), [127, 0, 128, 255]);
+    }
+
+    #[test]
+    fn surface_layers() {
+        let mut map: Map = room(ROOM, vec![]);
+        map.surface('g', Surface::Color([0, 255, 0, 255]));
+        map.floor_layer(".....\n.....\n...g.\n.....\n.....\n").unwrap();
+        map.set_ceil(2, 2, 'g');
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(75., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+
+        // Grass floor two cells ahead, default floor and grass ceiling in the cell between
+        assert_eq!(out_img.get(8, 11), [0, 255, 0, 255]);
+        assert_eq!(out_img.get(8, 14), [0, 0, 0, 255]);
+        assert_eq!(out_img.get(8, 0), [0, 255, 0, 255]);
+        assert_eq!(out_img.get(8, 4), [0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn lights() {
+        let mut map: Map = room(ROOM, vec![('0', Framebuffer::from_color(4, 4, [200, 200, 200, 255]))]);
+        map.ambient = 0.5;
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 8), [100, 100, 100, 255]);
+
+        // A red light next to the wall ahead
+        map.lights.push(light::Light::new(Vec2::new(220., 125.), [255, 0, 0], 50., 1.));
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        let [r, g, b, _] = out_img.get(8, 8);
+        assert!(r > 150 && g == 100 && b == 100);
+    }
+
+    #[test]
+    fn fog() {
+        let map: Map = room(ROOM, vec![]);
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let fog: Fog = Fog::linear(0., 150.).with_color([0, 0, 200]);
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), fog, &|| 0., 0., &view, &mut out_img);
+
+        // Walls, floors and ceilings all fade into the same opaque colour
+        assert_eq!(out_img.get(8, 8), [127, 0, 100, 255]);
+        assert!(out_img.get(8, 15)[2] > 0 && out_img.get(8, 0)[2] > 0);
+        assert!(out_img.data().iter().all(|c| c[3] == 255));
+
+        render(&map, std::iter::empty(), Ray::new(Vec2::new(125., 125.), 0.), fog.with_vignette(4.), &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(0, 0), [0, 0, 200, 255]);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel() {
+        let map: Map = Map::load("examples/res/demo.level").unwrap();
+        let view: Viewport = Viewport::new(203, 97);
+        let mut ray: Ray = map.player_start.unwrap();
+        ray.vangle = 0.2;
+        let fog: Fog = Fog::exp(0.003).with_color([30, 40, 50]).with_vignette(80.);
+
+        let mut serial: Framebuffer = view.framebuffer().with_depth();
+        let mut parallel: Framebuffer = view.framebuffer().with_depth();
+        let serial_columns: Vec<(Intersection, f32)> = render(&map, map.spawns.iter(), ray, fog, &|| 0., 0., &view, &mut serial);
+        // Bands are only used with more than one thread
+        let parallel_columns: Vec<(Intersection, f32)> = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap()
+            .install(|| render_parallel(&map, map.spawns.iter(), ray, fog, &|| 0., 0., &view, &mut parallel));
+        assert!(serial == parallel);
+        // Columns come back in screen order from every band
+        let key = |columns: &[(Intersection, f32)]| columns.iter().map(|(ins, angle)| (ins.itype, ins.distance, *angle)).collect::<Vec<_>>();
+        assert_eq!(parallel_columns.len(), view.w as usize);
+        assert_eq!(key(&serial_columns), key(&parallel_columns));
+    }
+
+    /// Floors and ceilings drawn a column at a time, the way `render_column` drew them before `render_planes`
+    fn column_planes(map: &Map, columns: &[Column], fog: Fog, horizon: f32, view: &Viewport, out_img: &mut Framebuffer) {
+        let width: usize = out_img.width();
+        let out_data: &mut [[u8; 4]] = out_img.data_mut();
+        let sky_fog: f32 = sky_fog(map, fog);
+
+        for (x, column) in columns.iter().enumerate() {
+            for span in &column.planes {
+                for y in span.y0.clamp(0, view.h)..span.y1.clamp(0, view.h) {
+                    let dy: f32 = -span.pitch_direction as f32 * (y as f32 + 0.5 - horizon);
+                    if dy <= 0. {
+                        continue;
+                    }
+
+                    let distance: f32 = span.dist_to_plane * view.proj_dist() / dy / column.cos_col;
+                    let new_pos: Vec2 = column.ray.along(distance);
+                    let out_i: usize = y as usize * width + x;
+                    let gpos: IVec2 = map.gpos(new_pos);
+                    let surface: &Surface = if span.pitch_direction < 0 { map.floor_at(gpos) } else { map.ceil_at(gpos) };
+                    let color: [u8; 4] = match surface {
+                        Surface::Texture(texture) => {
+                            let tc: Vec2 = new_pos % Vec2::new(texture.width() as f32, texture.height() as f32);
+                            opaque(texture.data()[tc.y as usize * texture.width() + tc.x as usize])
+                        }
+                        Surface::Color(color) => opaque(*color),
+                        Surface::Sky => {
+                            out_data[out_i] = fog.blend(sky_color(map, column.ray.angle, y, horizon, view), sky_fog);
+                            continue;
+                        }
+                    };
+
+                    let color: [u8; 4] = match light_at(map, new_pos) {
+                        Some(light) => lit(color, light),
+                        None => color,
+                    };
+                    out_data[out_i] = fog.blend(color, fog.amount(distance));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn planes_match_columns() {
+        let mut map: Map = room("0000000\n0.....0\n0.....0\n0.....0\n0000000\n", vec![]);
+        let pixels: Vec<[u8; 4]> = (0..64).map(|i| [i as u8 * 4, 255 - i as u8 * 4, (i % 8) as u8 * 30, 255]).collect();
+        map.floor_tex(Surface::Texture(Framebuffer::from_pixels(8, 8, pixels)));
+        map.ceil_tex(Surface::Sky);
+        let pixels: Vec<[u8; 4]> = (0..8).flat_map(|y| (0..64).map(move |x| [x as u8 * 4, y as u8 * 30, 0, 255])).collect();
+        map.sky(map::Sky::new(Framebuffer::from_pixels(64, 8, pixels)));
+        map.surface('g', Surface::Color([0, 255, 0, 255]));
+        map.ceil_layer(".......\n.......\n...g...\n.......\n.......\n").unwrap();
+        map.set_heights(IVec2::new(3, 2), map::CellHeights { floor: 0.25, ceil: Some(0.8) });
+        map.set_heights(IVec2::new(4, 1), map::CellHeights { floor: 0.5, ceil: None });
+        map.set_heights(IVec2::new(2, 3), map::CellHeights { floor: -0.25, ceil: Some(1.5) });
+        map.lights.push(light::Light::new(Vec2::new(200., 100.), [255, 200, 100], 120., 1.));
+
+        let view: Viewport = Viewport::new(64, 48);
+        let mut ray: Ray = Ray::new(Vec2::new(75., 125.), 0.2);
+        ray.vangle = 0.1;
+        let fog: Fog = Fog::linear(0., 300.).with_color([0, 0, 80]);
+        let horizon: f32 = view.horizon(ray.vangle);
+        let eye: f32 = eye_height(&map, ray.orig);
+
+        // Walls and step faces first, then the same spans drawn both ways
+        let mut walls: Framebuffer = view.framebuffer();
+        let columns: Vec<Column> = cast_rays(&map, ray, &view, 0..view.w).iter().enumerate().map(|(x, (ins, _, angle))| {
+            let mut cast_ray: Ray = Ray::new(ray.orig, *angle);
+            cast_ray.vangle = ray.vangle;
+            let mut column: Column = Column { ray: cast_ray, dir: cast_ray.dir(), cos_col: f32::cos(view.column_angle(x as i32)), planes: Vec::new() };
+            render_column(&map, &mut column, ins, x as i32, eye, fog, horizon, 0., &view, &mut walls);
+            column
+        }).collect();
+        let (mut rows, mut cols): (Framebuffer, Framebuffer) = (walls.clone(), walls);
+        render_planes(&map, &columns, fog, horizon, &view, &mut rows);
+        column_planes(&map, &columns, fog, horizon, &view, &mut cols);
+
+        // Steps split columns into several spans, ceilings other than the green one show the sky
+        assert!(columns.iter().any(|c| c.planes.len() > 2));
+        assert!(columns.iter().any(|c| c.planes.iter().any(|s| s.pitch_direction > 0 && s.y0 < s.y1)));
+        assert!(rows == cols);
+    }
+
+    #[test]
+    fn wrap_matches_fmod() {
+        for size in [1, 50, 64, 100, 200] {
+            for x in [0., 0.3, 49.99999, 50., 99.99999, 1234.567, 7999.9995, 16383.999] {
+                assert_eq!(wrap(x, size), (x % size as f32) as usize, "{} % {}", x, size);
+            }
+        }
+    }
+
+    #[test]
+    fn directional_sprites() {
+        let mut map: Map = room(ROOM, vec![
+            ('f', Framebuffer::from_color(4, 4, [0, 255, 0, 255])),
+            ('s', Framebuffer::from_pixels(2, 1, vec![[0, 0, 255, 255], [0, 0, 100, 255]])),
+            ('b', Framebuffer::from_color(4, 4, [255, 255, 0, 255])),
+        ]);
+        map.sprite_set('e', entity::SpriteSet::mirrored(vec!['f', 's', 'b']));
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let camera: Ray = Ray::new(Vec2::new(75., 125.), 0.);
+        let mut entity: Entity = Entity::new(Vec2::new(175., 125.), 'e', (40., 50.));
+
+        // Facing the camera, then away from it
+        entity.angle = PI;
+        render(&map, std::iter::once(&entity), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 8), [0, 255, 0, 255]);
+        entity.angle = 0.;
+        render(&map, std::iter::once(&entity), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 8), [255, 255, 0, 255]);
+
+        // Side views, the mirrored one flipped
+        entity.angle = PI / 2.;
+        render(&map, std::iter::once(&entity), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!((out_img.get(6, 8), out_img.get(9, 8)), ([0, 0, 255, 255], [0, 0, 100, 255]));
+        entity.angle = -PI / 2.;
+        render(&map, std::iter::once(&entity), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!((out_img.get(6, 8), out_img.get(9, 8)), ([0, 0, 100, 255], [0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn animations() {
+        let mut map: Map = room(ROOM, vec![('g', Framebuffer::from_color(4, 4, [0, 255, 0, 255]))]);
+        map.animation('0', animation::Animation::uniform(&['0', 'g'], 0.5, animation::LoopMode::Loop)).unwrap();
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let ray: Ray = Ray::new(Vec2::new(125., 125.), 0.);
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0.25, &view, &mut out_img);
+        assert_eq!(out_img.get(8, 8), [255, 0, 0, 255]);
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0.75, &view, &mut out_img);
+        assert_eq!(out_img.get(8, 8), [0, 255, 0, 255]);
+
+        // Entities animate too, here out of step with the walls
+        map.animation('e', animation::Animation::uniform(&['g', '0'], 0.5, animation::LoopMode::Loop)).unwrap();
+        let entity: Entity = Entity::new(Vec2::new(175., 125.), 'e', (40., 50.));
+        render(&map, std::iter::once(&entity), ray, Fog::NONE, &|| 0., 0.25, &view, &mut out_img);
+        assert_eq!((out_img.get(8, 8), out_img.get(0, 8)), ([0, 255, 0, 255], [255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn elevated_entities() {
+        let map: Map = room(ROOM, vec![('e', Framebuffer::from_color(4, 4, [0, 255, 0, 255]))]);
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let camera: Ray = Ray::new(Vec2::new(75., 125.), 0.);
+        // Hanging from the ceiling, above eye level
+        let entity: Entity = Entity::new(Vec2::new(175., 125.), 'e', (40., 20.)).with_z(30.);
+        render(&map, std::iter::once(&entity), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!((out_img.get(8, 4), out_img.get(8, 8)), ([0, 255, 0, 255], [255, 0, 0, 255]));
+
+        // Level shots pass over a low sprite and hit it when aimed down
+        let entity: Entity = entity.with_z(0.);
+        assert!(matches!(cast_ray(&map, std::iter::once(&entity), &[], camera).itype, IntersectionType::Wall { .. }));
+        let mut down: Ray = camera;
+        down.vangle = 0.15;
+        assert!(matches!(cast_ray(&map, std::iter::once(&entity), &[], down).itype, IntersectionType::Entity { .. }));
+        assert!(matches!(cast_ray(&map, std::iter::once(&entity.with_z(15.)), &[], camera).itype, IntersectionType::Entity { .. }));
+    }
+
+    #[test]
+    fn hit_scan_texels() {
+        // Left half see-through
+        let pixels: Vec<[u8; 4]> = (0..16).map(|i| if i % 4 < 2 { [0, 0, 0, 0] } else { [0, 255, 0, 255] }).collect();
+        let map: Map = room(ROOM, vec![('e', Framebuffer::from_pixels(4, 4, pixels))]);
+        let entity: Entity = Entity::new(Vec2::new(175., 125.), 'e', (40., 40.));
+
+        let hit: Hit = hit_scan(&map, std::iter::once(&entity), &[], Ray::new(Vec2::new(75., 125.), 0.1), 0.);
+        assert!(matches!(hit.ins.itype, IntersectionType::Entity { .. }));
+        assert_eq!((hit.texel, hit.pos.z), (Some((2, 1)), 25.));
+        let hit: Hit = hit_scan(&map, std::iter::once(&entity), &[], Ray::new(Vec2::new(75., 125.), -0.1), 0.);
+        assert!(matches!(hit.ins.itype, IntersectionType::Wall { .. }));
+        assert!(hit.texel.is_none());
+
+        // Aimed at the ceiling, over the sprite
+        let mut ray: Ray = Ray::new(Vec2::new(75., 125.), 0.1);
+        ray.vangle = -0.5;
+        let hit: Hit = hit_scan(&map, std::iter::once(&entity), &[], ray, 0.);
+        assert_eq!((hit.ins.itype, hit.pos.z, hit.texel), (IntersectionType::Ceiling { gpos: IVec2::new(2, 2) }, 50., None));
+        assert!((hit.ins.distance - 25. / f32::tan(0.5)).abs() < 0.01);
+    }
+
+    #[test]
+    fn hit_scan_picked_pixels() {
+        let mut map: Map = room(ROOM, vec![]);
+        for (i, tile) in "abcdefghi".chars().enumerate() {
+            map.surface(tile, Surface::Color([i as u8 * 28, 255 - i as u8 * 28, 100, 255]));
+        }
+        map.floor_layer(".....\n.abc.\n.def.\n.ghi.\n.....\n").unwrap();
+
+        let view: Viewport = Viewport::new(64, 64);
+        let mut out_img: Framebuffer = view.framebuffer();
+        let mut camera: Ray = Ray::new(Vec2::new(60., 60.), 0.6);
+        camera.vangle = 0.2;
+        render(&map, std::iter::empty(), camera, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+
+        // Off centre the floor under a pixel is further away than straight ahead
+        let mut floors: usize = 0;
+        for (x, y) in (0..64).flat_map(|x| (0..64).map(move |y| (x, y))) {
+            let ray: Ray = view.pick_ray(camera, Vec2::new(x as f32, y as f32)).unwrap();
+            if let IntersectionType::Floor { gpos } = hit_scan(&map, std::iter::empty(), &[], ray, 0.).ins.itype {
+                let Surface::Color(color) = map.floor_at(gpos) else { panic!() };
+                assert_eq!(out_img.get(x, y), *color, "pixel {} {}", x, y);
+                floors += 1;
+            }
+        }
+        assert!(floors > 500);
+    }
+
+    #[test]
+    fn hit_scan_surfaces() {
+        let mut map: Map = room("000000\n0....0\n000000\n", vec![]);
+        map.set_heights(IVec2::new(3, 1), map::CellHeights { floor: 0.6, ceil: Some(0.8) });
+        map.set_heights(IVec2::new(4, 1), map::CellHeights { floor: 0., ceil: Some(2.) });
+        let mut ray: Ray = Ray::new(Vec2::new(75., 75.), 0.);
+
+        // Straight ahead into the raised floor's step
+        let hit: Hit = hit_scan(&map, std::iter::empty(), &[], ray, 0.);
+        assert_eq!(hit.ins.itype, IntersectionType::Floor { gpos: IVec2::new(3, 1) });
+        assert!((hit.ins.distance - 75.).abs() < 0.01);
+
+        // Down onto the floor short of the step, and up under the lowered ceiling
+        ray.vangle = 0.5;
+        let hit: Hit = hit_scan(&map, std::iter::empty(), &[], ray, 0.);
+        assert_eq!((hit.ins.itype, hit.pos.z), (IntersectionType::Floor { gpos: IVec2::new(2, 1) }, 0.));
+        assert!((hit.pos.x - 75. - 25. / f32::tan(0.5)).abs() < 0.01);
+        ray.vangle = -0.15;
+        let hit: Hit = hit_scan(&map, std::iter::empty(), &[], ray, 0.);
+        assert_eq!((hit.ins.itype, hit.pos.z), (IntersectionType::Ceiling { gpos: IVec2::new(3, 1) }, 40.));
+
+        // Over the wall under a ceiling higher than it, stopped at its top
+        ray.orig = Vec2::new(225., 75.);
+        ray.vangle = -1.;
+        let hit: Hit = hit_scan(&map, std::iter::empty(), &[], ray, 0.);
+        assert!(matches!(hit.ins.itype, IntersectionType::Wall { .. }));
+        assert_eq!(hit.pos.z, 50.);
+        assert!((hit.ins.distance - 25.).abs() < 0.01);
+    }
+
+    #[test]
+    fn depth_buffer() {
+        let mut map: Map = room(ROOM, vec![('e', Framebuffer::from_color(4, 4, [0, 255, 0, 255]))]);
+        map.ceil_tex(Surface::Sky);
+
+        let view: Viewport = Viewport::new(16, 16);
+        let mut out_img: Framebuffer = view.framebuffer().with_depth();
+        let entity: Entity = Entity::new(Vec2::new(175., 125.), 'e', (20., 20.));
+        render(&map, std::iter::once(&entity), Ray::new(Vec2::new(125., 125.), 0.), Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        // Sky, wall, sprite in front of it, then the floor running up to the camera
+        let column: Vec<f32> = (0..16).map(|y| out_img.depth_at(8, y).unwrap()).collect();
+        assert_eq!((column[0], column[5]), (f32::INFINITY, 75.));
+        assert!((column[10] - 50.).abs() < 0.1);
+        assert!(column[15] < column[14] && column[15] < column[10]);
+        assert!(view.framebuffer().depth().is_none());
+    }
+
+    #[test]
+    fn sky() {
+        let mut map: Map = room(ROOM, vec![]);
+        map.ceil_tex(Surface::Sky);
+        let pixels: Vec<[u8; 4]> = (0..8).flat_map(|y| (0..64).map(move |x| [x as u8 * 4, y as u8 * 30, 0, 255])).collect();
+        map.sky(map::Sky::new(Framebuffer::from_pixels(64, 8, pixels)));
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let mut ray: Ray = Ray::new(Vec2::new(125., 125.), 0.);
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 0), [0, 60, 0, 255]);
+
+        // Scrolls a quarter of the way around with the view, looking up reaches the top of the panorama
+        ray.angle = PI / 2.;
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 0), [64, 60, 0, 255]);
+        ray.vangle = -1.;
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 0), [64, 0, 0, 255]);
+
+        // Fog reaches the sky only as far as configured
+        let fog: Fog = Fog::linear(0., 100.).with_color([0, 0, 255]);
+        render(&map, std::iter::empty(), ray, fog, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 0), [64, 0, 0, 255]);
+        map.sky.as_mut().unwrap().fog = 1.;
+        render(&map, std::iter::empty(), ray, fog, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 0), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn steps() {
+        let mut map: Map = room(ROOM, vec![]);
+        map.floor_tex(Surface::Color([0, 255, 0, 255]));
+
+        let (view, mut out_img): (Viewport, Framebuffer) = screen();
+        let ray: Ray = Ray::new(Vec2::new(125., 125.), 0.);
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 11), [255, 0, 0, 255]);
+
+        // Raised platform in front of the wall covers its bottom with floor and the step face
+        map.set_heights(IVec2::new(3, 2), map::CellHeights { floor: 0.25, ceil: None });
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 11), [0, 255, 0, 255]);
+        assert_eq!(out_img.get(8, 8), [255, 0, 0, 255]);
+
+        // Standing on a platform raises the eye, the far wall reaches further down the screen
+        map.set_heights(IVec2::new(3, 2), map::CellHeights { floor: 0., ceil: None });
+        map.set_heights(IVec2::new(1, 2), map::CellHeights { floor: 0.25, ceil: None });
+        let ray: Ray = Ray::new(Vec2::new(75., 125.), 0.);
+        render(&map, std::iter::empty(), ray, Fog::NONE, &|| 0., 0., &view, &mut out_img);
+        assert_eq!(out_img.get(8, 11), [255, 0, 0, 255]);
+        assert_eq!(out_img.get(8, 12), [0, 255, 0, 255]);
+        assert!(out_img.data().iter().all(|c| c[3] == 255));
+    }
+}
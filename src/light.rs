@@ -0,0 +1,96 @@
+use crate::map::Map;
+use crate::util::Ray;
+use glam::{Vec2, IVec2};
+
+/// Coloured point light, fading out towards `radius`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Light {
+    pub pos: Vec2,
+    pub color: [u8; 3],
+    pub radius: f32,
+    /// Brightness added at the centre, 1 lights a surface to its full texture colour
+    pub intensity: f32,
+    /// Grid walls block the light, costs a ray per lit pixel
+    pub shadows: bool,
+}
+
+impl Light {
+    pub fn new(pos: Vec2, color: [u8; 3], radius: f32, intensity: f32) -> Self {
+        Self { pos, color, radius, intensity, shadows: false }
+    }
+
+    pub fn with_shadows(mut self) -> Self {
+        self.shadows = true;
+        self
+    }
+
+    /// RGB brightness added at `pos`, ignoring shadows
+    pub fn contribution(&self, pos: Vec2) -> [f32; 3] {
+        let falloff: f32 = (1. - self.pos.distance(pos) / self.radius).max(0.);
+        let strength: f32 = self.intensity * falloff * falloff / 255.;
+        self.color.map(|c| c as f32 * strength)
+    }
+}
+
+impl Map {
+    /// Ambient light of a cell, overriding `ambient`
+    pub fn set_ambient(&mut self, gpos: IVec2, level: f32) {
+        self.cell_ambient.insert(gpos, level);
+    }
+
+    pub fn ambient_at(&self, gpos: IVec2) -> f32 {
+        self.cell_ambient.get(&gpos).copied().unwrap_or(self.ambient)
+    }
+
+    /// Whether lighting changes anything, a fully bright map without lights renders plain textures
+    pub fn is_lit(&self) -> bool {
+        !self.lights.is_empty() || self.ambient != 1. || !self.cell_ambient.is_empty()
+    }
+
+    /// RGB brightness at `pos`, ambient plus every light reaching it
+    pub fn light_at(&self, pos: Vec2) -> [f32; 3] {
+        let ambient: f32 = self.ambient_at(self.gpos(pos));
+        let mut light: [f32; 3] = [ambient; 3];
+        for l in &self.lights {
+            if l.pos.distance(pos) >= l.radius || (l.shadows && self.in_shadow(l, pos)) {
+                continue;
+            }
+
+            let added: [f32; 3] = l.contribution(pos);
+            for (c, a) in light.iter_mut().zip(added) {
+                *c += a;
+            }
+        }
+
+        light
+    }
+
+    fn in_shadow(&self, light: &Light, pos: Vec2) -> bool {
+        let to: Vec2 = pos - light.pos;
+        let distance: f32 = to.length();
+        // Points on a wall face are hit at their own distance, leave a little slack
+        distance > 1. && self.cast_ray(Ray::new(light.pos, f32::atan2(to.y, to.x))).distance < distance - 1.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::room;
+
+    #[test]
+    fn light_at() {
+        let mut map: Map = room("00000\n0...0\n0.0.0\n0...0\n00000\n", vec![]);
+        map.ambient = 0.;
+        map.set_ambient(IVec2::new(3, 3), 0.5);
+        map.lights.push(Light::new(Vec2::new(75., 125.), [255, 0, 255], 100., 1.));
+
+        assert_eq!(map.light_at(Vec2::new(75., 125.)), [1., 0., 1.]);
+        assert_eq!(map.light_at(Vec2::new(75., 175.)), [0.25, 0., 0.25]);
+        assert_eq!(map.light_at(Vec2::new(175., 175.)), [0.5; 3]);
+        // The pillar only blocks lights casting shadows
+        assert!(map.light_at(Vec2::new(170., 125.))[0] > 0.);
+        map.lights[0] = map.lights[0].with_shadows();
+        assert_eq!(map.light_at(Vec2::new(170., 125.)), [0.; 3]);
+    }
+}
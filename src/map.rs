@@ -1,15 +1,85 @@
 use crate::util::{Ray, Intersection, IntersectionType, Direction};
-use crate::entity::Entity;
-use macroquad::prelude as mq;
+use crate::entity::{Entity, SpriteSet};
+use crate::framebuffer::Framebuffer;
+use crate::door::{Door, DoorState};
+use crate::wall::{Axis, ThinWall, Segment};
+use crate::light::Light;
+use crate::animation::Animation;
+use crate::Fog;
 use glam::{Vec2, IVec2};
-use std::fs::File;
-use std::io::{BufReader, BufRead};
-use std::collections::HashMap;
+use std::fs;
+use std::fmt;
+use std::collections::{HashMap, HashSet};
 
 #[derive(Debug)]
 pub enum Surface {
-    Texture(mq::Image),
+    Texture(Framebuffer),
     Color([u8; 4]),
+    /// Open to the map's sky panorama
+    Sky,
+}
+
+/// Cylindrical panorama seen through `Surface::Sky` cells
+#[derive(Debug, Clone)]
+pub struct Sky {
+    /// Wraps once around the horizon, texels are square so it reaches `2π * h / w` radians above it
+    pub texture: Framebuffer,
+    /// How much of the map's fog the sky takes, 0 keeps it clear and 1 fogs it like the far distance
+    pub fog: f32,
+}
+
+impl Sky {
+    pub fn new(texture: Framebuffer) -> Self {
+        Self { texture, fog: 0. }
+    }
+
+    pub fn with_fog(mut self, fog: f32) -> Self {
+        self.fog = fog;
+        self
+    }
+}
+
+/// Texture slot of a map, used to remember where textures were loaded from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextureRef {
+    Tile(char),
+    Floor,
+    Ceil,
+    /// Tile of the floor and ceiling layers
+    Surface(char),
+    Sky,
+}
+
+/// Position in a layout, 1-based
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    /// `None` for layouts that didn't come from a file
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub enum MapError {
+    Io { path: String, source: std::io::Error },
+    Utf8 { file: Option<String>, source: std::str::Utf8Error },
+    /// Layout has no rows
+    Empty { file: Option<String> },
+    /// Row width differs from the first row, `at` points to where the row ends or overflows
+    Ragged { at: Location, expected: usize, found: usize },
+    /// Tile that isn't `'.'` and has no texture
+    UnknownTile { at: Location, tile: char },
+    /// Malformed level file declaration
+    Syntax { at: Location, message: String },
+    Texture { at: Location, path: String, source: png::DecodingError },
+    /// Map from another editor that is malformed or uses unsupported features
+    Import { file: Option<String>, message: String },
+    /// Texture has no known path and can't be referenced from a saved level
+    UnnamedTexture { texture: TextureRef },
+    /// Floor or ceiling layer doesn't match the size of the wall layout, sizes are (w, h)
+    LayerSize { at: Location, expected: (usize, usize), found: (usize, usize) },
+    /// Animation without frames, or with a frame tile that has no texture
+    Animation { tile: char, message: String },
 }
 
 #[derive(Debug)]
@@ -18,55 +88,155 @@ pub struct Map {
     pub w: f32,
     pub h: f32,
     pub tsize: f32,
-    pub(crate) textures: HashMap<char, mq::Image>,
+    pub(crate) textures: HashMap<char, Framebuffer>,
     pub(crate) wall_heights: HashMap<char, f32>,
     pub(crate) floor_tex: Surface,
     pub(crate) ceil_tex: Surface,
+    /// Start position and facing from the level file
+    pub player_start: Option<Ray>,
+    /// Entities declared by the level file
+    pub spawns: Vec<Entity>,
+    pub fog: Fog,
+    /// Texture paths written back by `save`
+    pub(crate) texture_paths: HashMap<TextureRef, String>,
+    /// Door tiles and their speed
+    pub(crate) door_tiles: HashMap<char, f32>,
+    pub(crate) doors: HashMap<IVec2, Door>,
+    pub(crate) thin_walls: HashMap<char, ThinWall>,
+    /// Off-grid walls, hit alongside the grid by `cast_ray`
+    pub segments: Vec<Segment>,
+    pub(crate) transparent: HashSet<char>,
+    pub(crate) cell_heights: HashMap<IVec2, CellHeights>,
+    /// Surfaces of the floor and ceiling layer tiles
+    pub(crate) surfaces: HashMap<char, Surface>,
+    pub(crate) sprite_sets: HashMap<char, SpriteSet>,
+    pub(crate) animations: HashMap<char, Animation>,
+    pub(crate) floor_layer: Option<String>,
+    pub(crate) ceil_layer: Option<String>,
+    pub(crate) sky: Option<Sky>,
+    /// Dynamic lights, free to move and change between frames
+    pub lights: Vec<Light>,
+    /// Light level of cells without their own, 1 shows textures at full brightness
+    pub ambient: f32,
+    pub(crate) cell_ambient: HashMap<IVec2, f32>,
 }
 
-impl Map {
-    pub fn new(path: &str, textures: HashMap<char, mq::Image>) -> Self {
-        let file = File::open(path).unwrap();
-        let reader = BufReader::new(file);
+/// Floor and ceiling elevation of an open cell in multiples of `tsize`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellHeights {
+    pub floor: f32,
+    /// `None` leaves the ceiling at the top of the walls, like cells without heights
+    pub ceil: Option<f32>,
+}
 
-        let mut layout: String = String::new();
-        let mut w: f32 = 0.;
-        let mut h: f32 = 0.;
-        for line in reader.lines() {
-            let s: String = line.unwrap();
-            layout.push_str(s.as_str());
+/// Validates the rows of a layout, returns them joined with the width and height.
+/// Tiles other than `'.'` must pass `known`.
+fn parse_rows(layout: &str, file: Option<&str>, first_line: usize, known: impl Fn(char) -> bool) -> Result<(String, usize, usize), MapError> {
+    let at = |line: usize, column: usize| Location { file: file.map(String::from), line, column };
+
+    let rows: Vec<&str> = layout.trim_end_matches(['\n', '\r']).lines().collect();
+    let w: usize = rows.first().map_or(0, |row| row.chars().count());
+    if w == 0 {
+        return Err(MapError::Empty { file: file.map(String::from) });
+    }
 
-            h += 1.;
-            w = s.len() as f32;
+    let mut filtered_layout: String = String::with_capacity(w * rows.len());
+    for (y, row) in rows.iter().enumerate() {
+        let len: usize = row.chars().count();
+        if len != w {
+            return Err(MapError::Ragged { at: at(first_line + y, len.min(w) + 1), expected: w, found: len });
         }
 
-        Self {
-            layout,
-            w,
-            h,
-            tsize: 50.,
-            textures,
-            wall_heights: HashMap::new(),
-            floor_tex: Surface::Color([0, 0, 0, 255]),
-            ceil_tex: Surface::Color([0, 0, 0, 255]),
+        for (x, c) in row.chars().enumerate() {
+            // `at()` indexes bytes, so tiles must be single byte
+            if !c.is_ascii() || (c != '.' && !known(c)) {
+                return Err(MapError::UnknownTile { at: at(first_line + y, x + 1), tile: c });
+            }
         }
+
+        filtered_layout.push_str(row);
     }
 
-    pub fn from(layout: &str, textures: HashMap<char, mq::Image>) -> Self {
-        let w: usize = layout.find('\n').unwrap();
-        let filtered_layout: String = layout.replace('\n', "");
-        let h: usize = filtered_layout.len() / w;
-        Self {
-            layout: filtered_layout,
-            w: w as f32,
-            h: h as f32,
-            tsize: 50.,
-            textures,
-            wall_heights: HashMap::new(),
-            floor_tex: Surface::Color([0, 0, 0, 255]),
-            ceil_tex: Surface::Color([0, 0, 0, 255]),
+    Ok((filtered_layout, w, rows.len()))
+}
+
+/// An animation needs frames, and a texture for each of their tiles
+pub(crate) fn check_animation(tile: char, animation: &Animation, textures: &HashMap<char, Framebuffer>) -> Result<(), MapError> {
+    if animation.frames.is_empty() {
+        return Err(MapError::Animation { tile, message: String::from("animation has no frames") });
+    }
+    if let Some(&(frame, _)) = animation.frames.iter().find(|f| !textures.contains_key(&f.0)) {
+        return Err(MapError::Animation { tile, message: format!("frame tile {:?} has no texture", frame) });
+    }
+    Ok(())
+}
+
+fn set_layer_tile(layer: &mut String, index: usize, c: char) {
+    layer.replace_range(index..index + 1, c.to_string().as_str());
+}
+
+/// Distance kept between movers and walls by `move_collidable`
+const COLLISION_RADIUS: f32 = 10.;
+/// Highest floor step `move_collidable` walks up, in multiples of `tsize`
+pub const MAX_STEP: f32 = 0.3;
+/// Lowest gap between floor and ceiling `move_collidable` walks into, in multiples of `tsize`
+pub const MIN_CLEARANCE: f32 = 0.6;
+
+/// Wall plane inside a cell, shared by doors and thin walls
+#[derive(Debug, Clone, Copy)]
+struct Plane {
+    axis: Axis,
+    offset: f32,
+    /// Fraction of the cell the plane has slid along its axis
+    slide: f32,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.as_deref().unwrap_or("<layout>"), self.line, self.column)
+    }
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::Io { path, source } => write!(f, "{}: {}", path, source),
+            MapError::Utf8 { file, source } => write!(f, "{}: {}", file.as_deref().unwrap_or("<layout>"), source),
+            MapError::Empty { file } => write!(f, "{}: layout is empty", file.as_deref().unwrap_or("<layout>")),
+            MapError::Ragged { at, expected, found } => write!(f, "{}: row is {} tiles wide, expected {}", at, found, expected),
+            MapError::UnknownTile { at, tile } => write!(f, "{}: unknown tile {:?} has no texture", at, tile),
+            MapError::Syntax { at, message } => write!(f, "{}: {}", at, message),
+            MapError::Texture { at, path, source } => write!(f, "{}: failed to load texture {}: {}", at, path, source),
+            MapError::Import { file, message } => write!(f, "{}: {}", file.as_deref().unwrap_or("<layout>"), message),
+            MapError::UnnamedTexture { texture } => write!(f, "{:?} texture has no path, set one with `Map::texture_path`", texture),
+            MapError::LayerSize { at, expected, found } =>
+                write!(f, "{}: layer is {}x{} tiles, expected {}x{}", at, found.0, found.1, expected.0, expected.1),
+            MapError::Animation { tile, message } => write!(f, "animation of tile {:?}: {}", tile, message),
         }
     }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::Io { source, .. } => Some(source),
+            MapError::Utf8 { source, .. } => Some(source),
+            MapError::Texture { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Map {
+    pub fn new(path: &str, textures: HashMap<char, Framebuffer>) -> Result<Self, MapError> {
+        let bytes: Vec<u8> = fs::read(path).map_err(|source| MapError::Io { path: path.to_string(), source })?;
+        let layout: &str = std::str::from_utf8(&bytes).map_err(|source| MapError::Utf8 { file: Some(path.to_string()), source })?;
+        Map::parse(layout, Some(path), 1, textures, HashMap::new(), HashMap::new())
+    }
+
+    pub fn from(layout: &str, textures: HashMap<char, Framebuffer>) -> Result<Self, MapError> {
+        Map::parse(layout, None, 1, textures, HashMap::new(), HashMap::new())
+    }
 
     pub fn floor_tex(&mut self, surface: Surface) {
         self.floor_tex = surface;
@@ -76,12 +246,211 @@ impl Map {
         self.ceil_tex = surface;
     }
 
+    /// Panorama shown by `Surface::Sky` floors and ceilings
+    pub fn sky(&mut self, sky: Sky) {
+        self.sky = Some(sky);
+    }
+
+    /// Surface of `tile` in the floor and ceiling layers
+    pub fn surface(&mut self, tile: char, surface: Surface) {
+        self.surfaces.insert(tile, surface);
+    }
+
+    /// Entities with texture `tile` are drawn with the frame of `set` facing the camera
+    pub fn sprite_set(&mut self, tile: char, set: SpriteSet) {
+        self.sprite_sets.insert(tile, set);
+    }
+
+    /// Walls and entities of `tile` cycle through the textures of the animation's frames, which must all have one
+    pub fn animation(&mut self, tile: char, animation: Animation) -> Result<(), MapError> {
+        check_animation(tile, &animation, &self.textures)?;
+        self.animations.insert(tile, animation);
+        Ok(())
+    }
+
+    /// Texture shown for `tile` at `time` seconds, the current frame of an animated tile
+    pub fn texture(&self, tile: char, time: f64) -> Option<&Framebuffer> {
+        let tile: char = self.animations.get(&tile).map_or(tile, |a| a.frame(time));
+        self.textures.get(&tile)
+    }
+
+    /// Floor tiles laid out like the walls, each cell uses the surface of its tile and `'.'` uses `floor_tex`
+    pub fn floor_layer(&mut self, layout: &str) -> Result<(), MapError> {
+        self.floor_layer = Some(self.parse_layer(layout, None, 1)?);
+        Ok(())
+    }
+
+    /// Ceiling tiles laid out like the walls, each cell uses the surface of its tile and `'.'` uses `ceil_tex`
+    pub fn ceil_layer(&mut self, layout: &str) -> Result<(), MapError> {
+        self.ceil_layer = Some(self.parse_layer(layout, None, 1)?);
+        Ok(())
+    }
+
+    /// Checks a floor or ceiling layout against the surfaces and the size of the wall layout
+    pub(crate) fn parse_layer(&self, layout: &str, file: Option<&str>, first_line: usize) -> Result<String, MapError> {
+        let (layer, w, h) = parse_rows(layout, file, first_line, |c| self.surfaces.contains_key(&c))?;
+        if (w, h) != (self.w as usize, self.h as usize) {
+            let at: Location = Location { file: file.map(String::from), line: first_line, column: 1 };
+            return Err(MapError::LayerSize { at, expected: (self.w as usize, self.h as usize), found: (w, h) });
+        }
+        Ok(layer)
+    }
+
+    /// Floor surface of a cell
+    pub fn floor_at(&self, gpos: IVec2) -> &Surface {
+        self.layer_surface(&self.floor_layer, gpos).unwrap_or(&self.floor_tex)
+    }
+
+    /// Ceiling surface of a cell
+    pub fn ceil_at(&self, gpos: IVec2) -> &Surface {
+        self.layer_surface(&self.ceil_layer, gpos).unwrap_or(&self.ceil_tex)
+    }
+
+    fn layer_surface(&self, layer: &Option<String>, gpos: IVec2) -> Option<&Surface> {
+        let layer: &str = layer.as_deref()?;
+        if self.out_of_bounds(gpos) {
+            return None;
+        }
+        let tile: char = layer.as_bytes()[(gpos.y * self.w as i32 + gpos.x) as usize] as char;
+        self.surfaces.get(&tile)
+    }
+
+    /// Changes one floor layer tile, `'.'` or a tile with a surface
+    pub fn set_floor(&mut self, gx: i32, gy: i32, c: char) {
+        set_layer_tile(self.floor_layer.get_or_insert_with(|| ".".repeat(self.layout.len())), gy as usize * self.w as usize + gx as usize, c);
+    }
+
+    /// Changes one ceiling layer tile, `'.'` or a tile with a surface
+    pub fn set_ceil(&mut self, gx: i32, gy: i32, c: char) {
+        set_layer_tile(self.ceil_layer.get_or_insert_with(|| ".".repeat(self.layout.len())), gy as usize * self.w as usize + gx as usize, c);
+    }
+
     pub fn wall_height(&mut self, wall: char, hmul: f32) {
         self.wall_heights.insert(wall, hmul);
     }
 
-    pub fn from_bytes(bytes: &[u8], textures: HashMap<char, mq::Image>) -> Self {
-        Map::from(std::str::from_utf8(bytes).unwrap(), textures)
+    /// Path saved levels reference the texture by, relative to the level file
+    pub fn texture_path(&mut self, texture: TextureRef, path: &str) {
+        self.texture_paths.insert(texture, path.to_string());
+    }
+
+    /// Turns `tile` into a thin wall, it stays passable on either side of the plane
+    pub fn thin_wall(&mut self, tile: char, thin: ThinWall) {
+        self.thin_walls.insert(tile, thin);
+    }
+
+    /// Lets rays through `tile`, it is drawn over whatever is behind it
+    pub fn transparent_tile(&mut self, tile: char) {
+        self.transparent.insert(tile);
+    }
+
+    /// Raises or lowers the floor and ceiling of a cell, in multiples of `tsize`
+    pub fn set_heights(&mut self, gpos: IVec2, heights: CellHeights) {
+        self.cell_heights.insert(gpos, heights);
+    }
+
+    pub fn heights(&self, gpos: IVec2) -> Option<CellHeights> {
+        self.cell_heights.get(&gpos).copied()
+    }
+
+    /// Floor elevation of a cell in world units
+    pub fn floor_height(&self, gpos: IVec2) -> f32 {
+        self.cell_heights.get(&gpos).map_or(0., |h| h.floor * self.tsize)
+    }
+
+    /// Ceiling elevation of a cell in world units, `None` if it follows the walls
+    pub fn ceil_height(&self, gpos: IVec2) -> Option<f32> {
+        self.cell_heights.get(&gpos).and_then(|h| h.ceil).map(|c| c * self.tsize)
+    }
+
+    /// Turns every `tile` cell into a closed door opening at `speed` (fraction per second).
+    /// Doors run between the walls on either side of them.
+    pub fn door_tile(&mut self, tile: char, speed: f32) {
+        self.door_tiles.insert(tile, speed);
+        for y in 0..self.h as i32 {
+            for x in 0..self.w as i32 {
+                if self.at(x, y) == tile {
+                    self.add_door(IVec2::new(x, y));
+                }
+            }
+        }
+    }
+
+    fn add_door(&mut self, gpos: IVec2) {
+        let solid = |x: i32, y: i32| self.out_of_bounds(IVec2::new(x, y)) || self.at(x, y) != '.';
+        let axis: Axis = if solid(gpos.x - 1, gpos.y) && solid(gpos.x + 1, gpos.y) { Axis::X } else { Axis::Y };
+        let speed: f32 = self.door_tiles[&self.at(gpos.x, gpos.y)];
+        self.doors.insert(gpos, Door::new(axis, speed));
+    }
+
+    pub fn door(&self, gpos: IVec2) -> Option<&Door> {
+        self.doors.get(&gpos)
+    }
+
+    pub fn door_state(&self, gpos: IVec2) -> Option<DoorState> {
+        self.door(gpos).map(Door::state)
+    }
+
+    /// Returns false if there's no door at `gpos`
+    pub fn open_door(&mut self, gpos: IVec2) -> bool {
+        self.doors.get_mut(&gpos).map(Door::open).is_some()
+    }
+
+    pub fn close_door(&mut self, gpos: IVec2) -> bool {
+        self.doors.get_mut(&gpos).map(Door::close).is_some()
+    }
+
+    pub fn toggle_door(&mut self, gpos: IVec2) -> bool {
+        self.doors.get_mut(&gpos).map(Door::toggle).is_some()
+    }
+
+    /// Animates doors, `dt` in seconds
+    pub fn update_doors(&mut self, dt: f32) {
+        for door in self.doors.values_mut() {
+            door.update(dt);
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], textures: HashMap<char, Framebuffer>) -> Result<Self, MapError> {
+        let layout: &str = std::str::from_utf8(bytes).map_err(|source| MapError::Utf8 { file: None, source })?;
+        Map::from(layout, textures)
+    }
+
+    /// `file` and `first_line` are only used for error reporting
+    /// Grid tiles need a texture, an animation or a sprite set
+    pub(crate) fn parse(layout: &str, file: Option<&str>, first_line: usize, textures: HashMap<char, Framebuffer>, animations: HashMap<char, Animation>, sprite_sets: HashMap<char, SpriteSet>) -> Result<Self, MapError> {
+        let known = |c: char| textures.contains_key(&c) || animations.contains_key(&c) || sprite_sets.contains_key(&c);
+        let (filtered_layout, w, h) = parse_rows(layout, file, first_line, known)?;
+
+        Ok(Self {
+            layout: filtered_layout,
+            w: w as f32,
+            h: h as f32,
+            tsize: 50.,
+            textures,
+            wall_heights: HashMap::new(),
+            floor_tex: Surface::Color([0, 0, 0, 255]),
+            ceil_tex: Surface::Color([0, 0, 0, 255]),
+            player_start: None,
+            spawns: Vec::new(),
+            fog: Fog::NONE,
+            texture_paths: HashMap::new(),
+            door_tiles: HashMap::new(),
+            doors: HashMap::new(),
+            thin_walls: HashMap::new(),
+            segments: Vec::new(),
+            transparent: HashSet::new(),
+            cell_heights: HashMap::new(),
+            surfaces: HashMap::new(),
+            sprite_sets,
+            animations,
+            floor_layer: None,
+            ceil_layer: None,
+            sky: None,
+            lights: Vec::new(),
+            ambient: 1.,
+            cell_ambient: HashMap::new(),
+        })
     }
 
     pub fn filter_entities(&mut self, entity_tags: &[char], entity_sizes: &[(f32, f32)]) -> Vec<Entity> {
@@ -106,14 +475,75 @@ impl Map {
         res
     }
 
+    /// First opaque wall hit, transparent walls are passed through
     pub fn cast_ray(&self, ray: Ray) -> Intersection {
-        let h: Intersection = self.cast_ray_h(ray);
-        let v: Intersection = self.cast_ray_v(ray);
+        self.cast_ray_layers(ray).0
+    }
+
+    /// First opaque wall hit and the transparent walls in front of it, nearest first
+    pub fn cast_ray_layers(&self, ray: Ray) -> (Intersection, Vec<Intersection>) {
+        let mut layers: Vec<Intersection> = Vec::new();
+
+        // Grid stepping starts at the first cell edge, so planes in the starting cell are checked here
+        let start: IVec2 = self.gpos(ray.orig);
+        let grid: Intersection = match self.plane(start).and_then(|plane| self.plane_hit(ray, start, plane)) {
+            Some(ins) if !self.is_transparent(start) => ins,
+            start_hit => {
+                layers.extend(start_hit);
+                let h: Intersection = self.cast_ray_h(ray, &mut layers);
+                let v: Intersection = self.cast_ray_v(ray, &mut layers);
+                if h.distance < v.distance { h } else { v }
+            }
+        };
+
+        let mut closest: Intersection = grid;
+        for (index, seg) in self.segments.iter().enumerate() {
+            let Some((t, u)) = seg.intersect(ray) else { continue };
+            // Texture repeats every `tsize` along the segment
+            let col: f32 = (u / self.tsize).fract();
+            let ins: Intersection = Intersection::new(IntersectionType::Segment { index, col, normal: seg.normal() }, t);
+            if self.transparent.contains(&seg.tile) {
+                layers.push(ins);
+            } else if ins.distance < closest.distance {
+                closest = ins;
+            }
+        }
+
+        layers.retain(|ins| ins.distance < closest.distance);
+        layers.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        // Both casts reach a plane in a cell entered exactly through its corner
+        layers.dedup_by(|a, b| a.itype == b.itype && a.distance == b.distance);
+        (closest, layers)
+    }
 
-        if h.distance < v.distance { h } else { v }
+    fn is_transparent(&self, gpos: IVec2) -> bool {
+        !self.out_of_bounds(gpos) && self.transparent.contains(&self.at(gpos.x, gpos.y))
     }
 
-    fn cast_ray_h(&self, ray: Ray) -> Intersection {
+    /// Handles a wall hit on `gpos` entered from `prev`, `Some` if it stops the ray.
+    /// Transparent hits go to `layers`, faces between cells of the same transparent tile are skipped.
+    fn grid_hit(&self, ins: Intersection, gpos: IVec2, prev: IVec2, layers: &mut Vec<Intersection>) -> Option<Intersection> {
+        if !self.is_transparent(gpos) {
+            return Some(ins);
+        }
+
+        let inner: bool = self.is_transparent(prev) && self.at(prev.x, prev.y) == self.at(gpos.x, gpos.y) && self.plane(gpos).is_none();
+        if !inner {
+            layers.push(ins);
+        }
+        None
+    }
+
+    /// Tile whose texture and height are used for a wall intersection
+    pub fn wall_tile(&self, ins: &Intersection) -> char {
+        match ins.itype {
+            IntersectionType::Wall { gpos, .. } => self.at(gpos.x, gpos.y),
+            IntersectionType::Segment { index, .. } => self.segments[index].tile,
+            _ => panic!(),
+        }
+    }
+
+    fn cast_ray_h(&self, ray: Ray, layers: &mut Vec<Intersection>) -> Intersection {
         let mut closest: Vec2 = Vec2::new(0., 0.);
         closest.y = ray.orig.y - ray.orig.y % self.tsize +
                         if ray.dir().y > 0. { self.tsize } else { 0. };
@@ -125,18 +555,28 @@ impl Map {
                 gpos.y -= 1;
             }
 
-            if self.out_of_bounds(gpos) || self.at(gpos.x, gpos.y) != '.' {
-                let face: Direction = if ray.dir().y < 0. { Direction::South } else { Direction::North };
-                return Intersection::new(IntersectionType::Wall { gpos, face }, (closest - ray.orig).length());
+            let face: Direction = if ray.dir().y < 0. { Direction::South } else { Direction::North };
+            let dy: f32 = if ray.dir().y < 0. { -self.tsize } else { self.tsize };
+
+            let prev: IVec2 = gpos - IVec2::new(0, dy.signum() as i32);
+            let hit: Option<Intersection> = if let Some(plane) = self.plane(gpos) {
+                self.plane_hit(ray, gpos, plane)
+            } else if self.out_of_bounds(gpos) || self.at(gpos.x, gpos.y) != '.' {
+                let col: f32 = (closest.x % self.tsize) / self.tsize;
+                Some(Intersection::new(IntersectionType::Wall { gpos, face, col }, (closest - ray.orig).length()))
+            } else {
+                None
+            };
+            if let Some(ins) = hit.and_then(|ins| self.grid_hit(ins, gpos, prev, layers)) {
+                return ins;
             }
 
-            let dy: f32 = if ray.dir().y < 0. { -self.tsize } else { self.tsize };
             closest.y += dy;
             closest.x += dy / f32::tan(ray.angle);
         }
     }
 
-    fn cast_ray_v(&self, ray: Ray) -> Intersection {
+    fn cast_ray_v(&self, ray: Ray, layers: &mut Vec<Intersection>) -> Intersection {
         let mut closest: Vec2 = Vec2::new(0., 0.);
         closest.x = ray.orig.x - ray.orig.x % self.tsize +
                         if ray.dir().x > 0. { self.tsize } else { 0. };
@@ -148,30 +588,176 @@ impl Map {
                 gpos.x -= 1;
             }
 
-            if self.out_of_bounds(gpos) || self.at(gpos.x, gpos.y) != '.' {
-                let face: Direction = if ray.dir().x < 0. { Direction::East } else { Direction::West };
-                return Intersection::new(IntersectionType::Wall { gpos, face }, (closest - ray.orig).length());
+            let face: Direction = if ray.dir().x < 0. { Direction::East } else { Direction::West };
+            let dx: f32 = if ray.dir().x < 0. { -self.tsize } else { self.tsize };
+
+            let prev: IVec2 = gpos - IVec2::new(dx.signum() as i32, 0);
+            let hit: Option<Intersection> = if let Some(plane) = self.plane(gpos) {
+                self.plane_hit(ray, gpos, plane)
+            } else if self.out_of_bounds(gpos) || self.at(gpos.x, gpos.y) != '.' {
+                let col: f32 = (closest.y % self.tsize) / self.tsize;
+                Some(Intersection::new(IntersectionType::Wall { gpos, face, col }, (closest - ray.orig).length()))
+            } else {
+                None
+            };
+            if let Some(ins) = hit.and_then(|ins| self.grid_hit(ins, gpos, prev, layers)) {
+                return ins;
             }
 
-            let dx: f32 = if ray.dir().x < 0. { -self.tsize } else { self.tsize };
             closest.x += dx;
             closest.y += dx * f32::tan(ray.angle);
         }
     }
 
+    /// Door or thin wall plane inside the cell
+    fn plane(&self, gpos: IVec2) -> Option<Plane> {
+        if let Some(door) = self.doors.get(&gpos) {
+            return Some(Plane { axis: door.axis, offset: 0.5, slide: door.open });
+        }
+
+        if self.out_of_bounds(gpos) {
+            return None;
+        }
+        self.thin_walls.get(&self.at(gpos.x, gpos.y))
+            .map(|t| Plane { axis: t.axis, offset: t.offset, slide: 0. })
+    }
+
+    /// Intersection with the plane in `gpos`, `None` if the ray misses the part of it inside the cell
+    fn plane_hit(&self, ray: Ray, gpos: IVec2, plane: Plane) -> Option<Intersection> {
+        let dir: Vec2 = ray.dir();
+        let cell: Vec2 = gpos.as_vec2() * self.tsize;
+        // Distance to the plane and hit position along it, relative to the cell
+        let (t, u, face) = match plane.axis {
+            Axis::X => {
+                let t: f32 = (cell.y + plane.offset * self.tsize - ray.orig.y) / dir.y;
+                (t, ray.orig.x + t * dir.x - cell.x, if dir.y < 0. { Direction::South } else { Direction::North })
+            }
+            Axis::Y => {
+                let t: f32 = (cell.x + plane.offset * self.tsize - ray.orig.x) / dir.x;
+                (t, ray.orig.y + t * dir.y - cell.y, if dir.x < 0. { Direction::East } else { Direction::West })
+            }
+        };
+
+        // Sliding moves the texture with the wall, the part past the cell is hidden in the neighbouring wall
+        let slide: f32 = plane.slide * self.tsize;
+        if t > 0. && u >= slide && u < self.tsize {
+            let col: f32 = (u - slide) / self.tsize;
+            Some(Intersection::new(IntersectionType::Wall { gpos, face, col }, t))
+        } else {
+            None
+        }
+    }
+
+    /// Whether movement is blocked by the cell, thin walls only block crossing their plane
+    pub fn is_solid(&self, gpos: IVec2) -> bool {
+        if let Some(door) = self.doors.get(&gpos) {
+            return !door.passable();
+        }
+
+        let tile: char = self.at(gpos.x, gpos.y);
+        tile != '.' && !self.thin_walls.contains_key(&tile)
+    }
+
+    /// Whether the floor of `to` is too high to step up to from `from`, or its ceiling too low to fit under
+    fn blocks_step(&self, from: IVec2, to: IVec2) -> bool {
+        if from == to {
+            return false;
+        }
+
+        let floor: f32 = self.floor_height(to);
+        let too_low: bool = self.ceil_height(to).is_some_and(|ceil| ceil - floor < MIN_CLEARANCE * self.tsize);
+        floor - self.floor_height(from) > MAX_STEP * self.tsize || too_low
+    }
+
+    /// Cells the ray passes through up to `max_dist`, with the distance at which it leaves each
+    pub(crate) fn traverse(&self, ray: Ray, max_dist: f32) -> Vec<(IVec2, f32)> {
+        let dir: Vec2 = ray.dir();
+        let mut gpos: IVec2 = self.gpos(ray.orig);
+        let step: IVec2 = IVec2::new(if dir.x < 0. { -1 } else { 1 }, if dir.y < 0. { -1 } else { 1 });
+        // Distance between grid lines along the ray, and to the next one
+        let delta: Vec2 = Vec2::new(self.tsize / dir.x.abs(), self.tsize / dir.y.abs());
+        let mut next: Vec2 = Vec2::new(
+            if dir.x < 0. { ray.orig.x - gpos.x as f32 * self.tsize } else { (gpos.x + 1) as f32 * self.tsize - ray.orig.x } / dir.x.abs(),
+            if dir.y < 0. { ray.orig.y - gpos.y as f32 * self.tsize } else { (gpos.y + 1) as f32 * self.tsize - ray.orig.y } / dir.y.abs(),
+        );
+
+        // Hits on cell edges can land a hair short of the grid line
+        let limit: f32 = max_dist - 0.001 * self.tsize;
+        let mut cells: Vec<(IVec2, f32)> = Vec::new();
+        loop {
+            let exit: f32 = next.x.min(next.y);
+            if exit >= limit || self.out_of_bounds(gpos) {
+                cells.push((gpos, max_dist));
+                return cells;
+            }
+
+            cells.push((gpos, exit));
+            if next.x < next.y {
+                gpos.x += step.x;
+                next.x += delta.x;
+            } else {
+                gpos.y += step.y;
+                next.y += delta.y;
+            }
+        }
+    }
+
+    /// Whether moving from `from` to `to` crosses a thin wall running along `axis`
+    fn crosses_thin_wall(&self, from: Vec2, to: Vec2, axis: Axis) -> bool {
+        [self.gpos(from), self.gpos(to)].into_iter().any(|gpos| {
+            let Some(thin) = self.thin_walls.get(&self.at(gpos.x, gpos.y)).filter(|t| t.axis == axis) else {
+                return false;
+            };
+            let (a, b, plane) = match axis {
+                Axis::X => (from.y, to.y, (gpos.y as f32 + thin.offset) * self.tsize),
+                Axis::Y => (from.x, to.x, (gpos.x as f32 + thin.offset) * self.tsize),
+            };
+            (a - plane).signum() != (b - plane).signum()
+        })
+    }
+
     pub fn move_collidable(&self, before: Vec2, after: Vec2) -> Vec2 {
         let offset: Vec2 = Vec2::new(
-            if after.x - before.x > 0. { 10. } else { -10. },
-            if after.y - before.y > 0. { 10. } else { -10. }
+            if after.x - before.x > 0. { COLLISION_RADIUS } else { -COLLISION_RADIUS },
+            if after.y - before.y > 0. { COLLISION_RADIUS } else { -COLLISION_RADIUS }
         );
 
         let gpos: IVec2 = self.gpos(before + offset);
         let new_gpos: IVec2 = self.gpos(after + offset);
 
-        Vec2::new(
-            if self.at(new_gpos.x, gpos.y) == '.' { after.x } else { before.x },
-            if self.at(gpos.x, new_gpos.y) == '.' { after.y } else { before.y }
-        )
+        let probe: Vec2 = before + offset;
+        let from: IVec2 = self.gpos(before);
+        let blocked_x: bool = self.is_solid(IVec2::new(new_gpos.x, gpos.y)) ||
+            self.blocks_step(from, IVec2::new(new_gpos.x, gpos.y)) ||
+            self.crosses_thin_wall(probe, Vec2::new(after.x + offset.x, probe.y), Axis::Y);
+        let blocked_y: bool = self.is_solid(IVec2::new(gpos.x, new_gpos.y)) ||
+            self.blocks_step(from, IVec2::new(gpos.x, new_gpos.y)) ||
+            self.crosses_thin_wall(probe, Vec2::new(probe.x, after.y + offset.y), Axis::X);
+
+        let pos: Vec2 = Vec2::new(
+            if !blocked_x { after.x } else { before.x },
+            if !blocked_y { after.y } else { before.y }
+        );
+        self.slide_segments(before, pos)
+    }
+
+    /// Keeps a move at least `COLLISION_RADIUS` away from segments, sliding along them instead of stopping
+    fn slide_segments(&self, before: Vec2, mut pos: Vec2) -> Vec2 {
+        for seg in &self.segments {
+            // Drop the part of the step going through the segment
+            if seg.crosses(before, pos) {
+                let normal: Vec2 = seg.normal();
+                pos -= normal * (pos - before).dot(normal);
+            }
+
+            let closest: Vec2 = seg.closest_point(pos);
+            let dist: f32 = pos.distance(closest);
+            if dist > 0. && dist < COLLISION_RADIUS {
+                pos = closest + (pos - closest) / dist * COLLISION_RADIUS;
+            }
+        }
+
+        pos
     }
 
     pub fn gpos(&self, pos: Vec2) -> IVec2 {
@@ -188,6 +774,12 @@ impl Map {
     pub fn set(&mut self, gx: i32, gy: i32, c: char) {
         let index: usize = gy as usize * self.w as usize + gx as usize;
         self.layout.replace_range(index..index + 1, c.to_string().as_str());
+
+        let gpos: IVec2 = IVec2::new(gx, gy);
+        self.doors.remove(&gpos);
+        if self.door_tiles.contains_key(&c) {
+            self.add_door(gpos);
+        }
     }
 
     pub fn out_of_bounds(&self, gpos: IVec2) -> bool {
@@ -198,11 +790,169 @@ impl Map {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::f32::consts::PI;
+
+    fn textures(tiles: &[char]) -> HashMap<char, Framebuffer> {
+        tiles.iter().map(|&c| (c, Framebuffer::new(1, 1))).collect()
+    }
 
     #[test]
     fn gpos() {
-        let map: Map = Map::new("res/map", HashMap::new());
+        let map: Map = Map::new("examples/res/map", textures(&['0'])).unwrap();
         assert_eq!(map.gpos(Vec2::new(160., 150.)), IVec2::new(3, 3));
         assert_eq!(map.gpos(Vec2::new(200., 140.)), IVec2::new(4, 2));
     }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(Map::from("", textures(&[])), Err(MapError::Empty { .. })));
+        assert!(matches!(Map::from_bytes(&[0xff, b'\n'], textures(&[])), Err(MapError::Utf8 { .. })));
+        assert!(matches!(Map::new("examples/res/missing", textures(&[])), Err(MapError::Io { .. })));
+
+        let Err(MapError::Ragged { at, expected, found }) = Map::from("000\n0.\n000\n", textures(&['0'])) else { panic!() };
+        assert_eq!((at.line, at.column, expected, found), (2, 3, 3, 2));
+
+        let Err(MapError::UnknownTile { at, tile }) = Map::from("000\n0.x\n000\n", textures(&['0'])) else { panic!() };
+        assert_eq!((at.line, at.column, tile), (2, 3, 'x'));
+        assert_eq!(at.to_string(), "<layout>:2:3");
+    }
+
+    #[test]
+    fn doors() {
+        let mut map: Map = Map::from("00000\n0.d.0\n00000\n", textures(&['0', 'd'])).unwrap();
+        map.door_tile('d', 2.);
+        let gpos: IVec2 = IVec2::new(2, 1);
+        assert_eq!(map.door(gpos).unwrap().axis, Axis::Y);
+
+        // Closed door is a thin wall through the middle of its cell
+        let ray: Ray = Ray::new(Vec2::new(75., 70.), 0.);
+        let ins: Intersection = map.cast_ray(ray);
+        assert_eq!(ins.wall_gpos(), gpos);
+        assert!((ins.distance - 50.).abs() < 0.01);
+        assert_eq!(map.move_collidable(Vec2::new(110., 75.), Vec2::new(120., 75.)), Vec2::new(110., 75.));
+
+        map.open_door(gpos);
+        map.update_doors(0.25);
+        assert_eq!(map.door_state(gpos), Some(DoorState::Opening));
+        // Half open, the door slid towards +y
+        assert_eq!(map.cast_ray(ray).wall_gpos(), IVec2::new(4, 1));
+        assert_eq!(map.cast_ray(Ray::new(Vec2::new(75., 85.), 0.)).wall_gpos(), gpos);
+
+        map.update_doors(1.);
+        assert_eq!(map.door_state(gpos), Some(DoorState::Open));
+        assert_eq!(map.cast_ray(ray).wall_gpos(), IVec2::new(4, 1));
+        assert_eq!(map.move_collidable(Vec2::new(110., 75.), Vec2::new(120., 75.)), Vec2::new(120., 75.));
+
+        map.toggle_door(gpos);
+        assert_eq!(map.door_state(gpos), Some(DoorState::Closing));
+    }
+
+    #[test]
+    fn thin_walls() {
+        let mut map: Map = Map::from("00000\n0.f.0\n00000\n", textures(&['0', 'f'])).unwrap();
+        map.thin_wall('f', ThinWall::new(Axis::Y, 0.25));
+        let gpos: IVec2 = IVec2::new(2, 1);
+        assert!(!map.is_solid(gpos));
+
+        let ins: Intersection = map.cast_ray(Ray::new(Vec2::new(75., 70.), 0.));
+        assert!((ins.distance - 37.5).abs() < 0.01);
+        assert!(matches!(ins.itype, IntersectionType::Wall { face: Direction::West, col, .. } if (col - 0.4).abs() < 0.01));
+
+        // Plane in the starting cell, hit from both sides
+        let ins: Intersection = map.cast_ray(Ray::new(Vec2::new(105., 70.), 0.));
+        assert_eq!(ins.wall_gpos(), gpos);
+        assert!((ins.distance - 7.5).abs() < 0.01);
+        let ins: Intersection = map.cast_ray(Ray::new(Vec2::new(120., 70.), PI));
+        assert!(matches!(ins.itype, IntersectionType::Wall { face: Direction::East, .. }));
+        assert!((ins.distance - 7.5).abs() < 0.01);
+
+        // Blocks crossing the plane, free movement along it
+        assert_eq!(map.move_collidable(Vec2::new(100., 75.), Vec2::new(105., 75.)), Vec2::new(100., 75.));
+        assert_eq!(map.move_collidable(Vec2::new(120., 60.), Vec2::new(120., 70.)), Vec2::new(120., 70.));
+        assert_eq!(map.move_collidable(Vec2::new(130., 75.), Vec2::new(140., 75.)), Vec2::new(140., 75.));
+
+        // Entering the cell through its top edge, across the plane's axis
+        let mut map: Map = Map::from("00000\n0...0\n0.f.0\n0...0\n00000\n", textures(&['0', 'f'])).unwrap();
+        map.thin_wall('f', ThinWall::centered(Axis::Y));
+        let ins: Intersection = map.cast_ray(Ray::new(Vec2::new(60., 60.), f32::atan2(60., 65.)));
+        assert_eq!(ins.wall_gpos(), IVec2::new(2, 2));
+        assert!((ins.distance - Vec2::new(65., 60.).length()).abs() < 0.01);
+    }
+
+    #[test]
+    fn segments() {
+        let mut map: Map = Map::from("00000\n0...0\n0...0\n0...0\n00000\n", textures(&['0'])).unwrap();
+        let seg: Segment = Segment::diagonal(IVec2::new(2, 2), map.tsize, true, '0');
+        map.segments.push(seg);
+
+        let ins: Intersection = map.cast_ray(Ray::new(Vec2::new(75., 125.), 0.));
+        assert!((ins.distance - 50.).abs() < 0.01);
+        let IntersectionType::Segment { index: 0, col, normal } = ins.itype else { panic!() };
+        assert!((col - 0.707).abs() < 0.01);
+        assert!(normal.abs_diff_eq(Vec2::new(-1., 1.).normalize(), 0.001));
+        assert_eq!(map.wall_tile(&ins), '0');
+
+        // Pushed back out while keeping the movement along the segment
+        let pos: Vec2 = map.move_collidable(Vec2::new(95., 125.), Vec2::new(115., 125.));
+        assert!((seg.closest_point(pos).distance(pos) - COLLISION_RADIUS).abs() < 0.01);
+        assert!(pos.y > 125.);
+        // Fast moves can't tunnel through
+        let pos: Vec2 = map.move_collidable(Vec2::new(110., 125.), Vec2::new(140., 125.));
+        assert!((pos - seg.closest_point(pos)).dot(normal) > 0.);
+    }
+
+    #[test]
+    fn transparent() {
+        let mut map: Map = Map::from("000000\n0.gg.0\n000000\n", textures(&['0', 'g'])).unwrap();
+        map.transparent_tile('g');
+
+        // One layer for the whole block, the face between the two glass cells is skipped
+        let (ins, layers) = map.cast_ray_layers(Ray::new(Vec2::new(75., 75.), 0.));
+        assert_eq!(ins.wall_gpos(), IVec2::new(5, 1));
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].wall_gpos(), IVec2::new(2, 1));
+        assert!((layers[0].distance - 25.).abs() < 0.01);
+
+        // Layers stay ordered nearest first with thin walls and segments mixed in
+        map.set(4, 1, 'g');
+        map.thin_wall('g', ThinWall::centered(Axis::Y));
+        map.segments.push(Segment::new(Vec2::new(90., 50.), Vec2::new(90., 100.), 'g'));
+        let (_, layers) = map.cast_ray_layers(Ray::new(Vec2::new(75., 75.), 0.));
+        let distances: Vec<f32> = layers.iter().map(|ins| ins.distance.round()).collect();
+        assert_eq!(distances, vec![15., 50., 100., 150.]);
+    }
+
+    #[test]
+    fn heights() {
+        let mut map: Map = Map::from("00000\n0...0\n00000\n", textures(&['0'])).unwrap();
+        let cells: Vec<(IVec2, f32)> = map.traverse(Ray::new(Vec2::new(75., 75.), 0.), 125.);
+        assert_eq!(cells, vec![(IVec2::new(1, 1), 25.), (IVec2::new(2, 1), 75.), (IVec2::new(3, 1), 125.)]);
+
+        let (before, after): (Vec2, Vec2) = (Vec2::new(75., 75.), Vec2::new(95., 75.));
+        map.set_heights(IVec2::new(2, 1), CellHeights { floor: 0.5, ceil: None });
+        assert_eq!(map.floor_height(IVec2::new(2, 1)), 25.);
+        assert_eq!(map.move_collidable(before, after), before);
+
+        // Low steps are walked up, any drop is walked down
+        map.set_heights(IVec2::new(2, 1), CellHeights { floor: 0.2, ceil: None });
+        assert_eq!(map.move_collidable(before, after), after);
+        assert_eq!(map.move_collidable(Vec2::new(115., 75.), Vec2::new(95., 75.)), Vec2::new(95., 75.));
+
+        map.set_heights(IVec2::new(2, 1), CellHeights { floor: 0., ceil: Some(0.5) });
+        assert_eq!(map.move_collidable(before, after), before);
+    }
+
+    #[test]
+    fn animations() {
+        use crate::animation::LoopMode;
+
+        let mut map: Map = Map::from("000\n0.0\n000\n", textures(&['0', 'a'])).unwrap();
+        let Err(MapError::Animation { tile: 'w', .. }) = map.animation('w', Animation::new(Vec::new(), LoopMode::Loop)) else { panic!() };
+        let Err(MapError::Animation { message, .. }) = map.animation('w', Animation::uniform(&['a', 'x'], 0.5, LoopMode::Loop)) else { panic!() };
+        assert!(message.contains("'x'"));
+        assert!(map.animations.is_empty());
+
+        map.animation('0', Animation::uniform(&['0', 'a'], 0.5, LoopMode::Loop)).unwrap();
+        assert_eq!(map.texture('0', 0.75).unwrap(), &map.textures[&'a']);
+    }
 }
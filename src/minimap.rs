@@ -0,0 +1,253 @@
+use crate::util::{Ray, Intersection, IntersectionType};
+use crate::map::Map;
+use crate::entity::Entity;
+use crate::framebuffer::Framebuffer;
+use glam::{Vec2, IVec2};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+/// Top-down view of the map around the camera, drawn into a rect of a framebuffer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Minimap {
+    /// Top left corner of the rect in framebuffer pixels
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+    /// Pixels per world unit, the camera sits in the middle of the rect
+    pub zoom: f32,
+    /// Turns the map with the camera so it always looks up
+    pub rotate: bool,
+    /// Open cells, and everything outside the map
+    pub floor: [u8; 4],
+    pub background: [u8; 4],
+    /// Cells not explored yet
+    pub unexplored: [u8; 4],
+    /// Blended over what the camera sees, alpha is the share
+    pub fov: [u8; 4],
+    pub entity: [u8; 4],
+    pub player: [u8; 4],
+}
+
+/// Cells seen so far, the minimap hides the rest
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explored {
+    w: i32,
+    cells: Vec<bool>,
+}
+
+impl Explored {
+    /// Nothing seen yet
+    pub fn new(map: &Map) -> Self {
+        Self { w: map.w as i32, cells: vec![false; (map.w * map.h) as usize] }
+    }
+
+    /// Marks the cells seen from `ray` up to and including the walls hit by `columns`, as returned by `render`
+    pub fn reveal(&mut self, map: &Map, ray: Ray, columns: &[(Intersection, f32)]) {
+        for &(ins, angle) in columns {
+            for (gpos, _) in map.traverse(Ray::new(ray.orig, angle), ins.distance) {
+                self.mark(map, gpos);
+            }
+            if let IntersectionType::Wall { gpos, .. } = ins.itype {
+                self.mark(map, gpos);
+            }
+        }
+    }
+
+    pub fn is_explored(&self, gpos: IVec2) -> bool {
+        gpos.x >= 0 && gpos.x < self.w && gpos.y >= 0 && self.cells.get((gpos.y * self.w + gpos.x) as usize).copied().unwrap_or(false)
+    }
+
+    fn mark(&mut self, map: &Map, gpos: IVec2) {
+        if !map.out_of_bounds(gpos) {
+            self.cells[(gpos.y * self.w + gpos.x) as usize] = true;
+        }
+    }
+}
+
+impl Minimap {
+    /// `w` by `h` pixels at (x, y), showing a tile every 10 pixels of a 50 unit grid
+    pub fn new(x: usize, y: usize, w: usize, h: usize) -> Self {
+        Self {
+            x, y, w, h,
+            zoom: 0.2,
+            rotate: false,
+            floor: [40, 40, 40, 255],
+            background: [0, 0, 0, 255],
+            unexplored: [15, 15, 15, 255],
+            fov: [255, 255, 160, 70],
+            entity: [220, 40, 40, 255],
+            player: [60, 220, 60, 255],
+        }
+    }
+
+    pub fn with_zoom(mut self, zoom: f32) -> Self {
+        self.zoom = zoom;
+        self
+    }
+
+    pub fn rotated(mut self) -> Self {
+        self.rotate = true;
+        self
+    }
+
+    /// Walls take the average colour of their texture. The fov cone follows the `columns` returned by `render`,
+    /// and with `explored` only cells seen so far and the entities in them are shown.
+    pub fn draw<'a, I>(&self, map: &Map, entities: I, ray: Ray, columns: &[(Intersection, f32)], explored: Option<&Explored>, out_img: &mut Framebuffer) where I: Iterator<Item = &'a Entity> {
+        assert!(self.x + self.w <= out_img.width() && self.y + self.h <= out_img.height(), "minimap rect out of bounds");
+        let seen = |gpos: IVec2| explored.map(|e| e.is_explored(gpos)).unwrap_or(true);
+        let cell = |pos: Vec2| (pos / map.tsize).floor().as_ivec2();
+
+        // Cells under every pixel, walls coloured like their texture
+        let mut colors: HashMap<char, [u8; 4]> = HashMap::new();
+        for py in 0..self.h {
+            for px in 0..self.w {
+                let gpos: IVec2 = cell(self.unproject(ray, Vec2::new(px as f32 + 0.5, py as f32 + 0.5)));
+                let color: [u8; 4] = if map.out_of_bounds(gpos) {
+                    self.background
+                } else if !seen(gpos) {
+                    self.unexplored
+                } else {
+                    match map.at(gpos.x, gpos.y) {
+                        '.' => self.floor,
+                        tile => *colors.entry(tile).or_insert_with(|| map.texture(tile, 0.).map_or([128, 128, 128, 255], average)),
+                    }
+                };
+                out_img.set(self.x + px, self.y + py, color);
+            }
+        }
+
+        for segment in &map.segments {
+            if seen(cell(segment.a)) || seen(cell(segment.b)) {
+                let color: [u8; 4] = *colors.entry(segment.tile).or_insert_with(|| map.texture(segment.tile, 0.).map_or([128, 128, 128, 255], average));
+                self.line(self.project(ray, segment.a), self.project(ray, segment.b), color, out_img);
+            }
+        }
+
+        // Fan of the rays up to the walls they hit
+        let hits: Vec<Vec2> = columns.iter()
+            .map(|(ins, angle)| self.project(ray, Ray::new(ray.orig, *angle).along(ins.distance)))
+            .collect();
+        let eye: Vec2 = self.project(ray, ray.orig);
+        let mut cone: Vec<bool> = vec![false; self.w * self.h];
+        for pair in hits.windows(2) {
+            self.fill_triangle([eye, pair[0], pair[1]], &mut cone);
+        }
+        for (i, _) in cone.iter().enumerate().filter(|c| *c.1) {
+            let (px, py): (usize, usize) = (self.x + i % self.w, self.y + i / self.w);
+            out_img.set(px, py, crate::blend(out_img.get(px, py), self.fov));
+        }
+
+        for ent in entities.filter(|e| seen(cell(e.pos))) {
+            self.marker(self.project(ray, ent.pos), 2, self.entity, out_img);
+        }
+        self.marker(eye, 2, self.player, out_img);
+    }
+
+    /// Rotation from minimap to world, a quarter turn past the camera angle so it looks up
+    fn turn(&self, ray: Ray) -> Vec2 {
+        Vec2::from_angle(if self.rotate { ray.angle + PI / 2. } else { 0. })
+    }
+
+    /// Position in the rect of world point `pos`
+    fn project(&self, ray: Ray, pos: Vec2) -> Vec2 {
+        let turn: Vec2 = self.turn(ray);
+        Vec2::new(turn.x, -turn.y).rotate(pos - ray.orig) * self.zoom + Vec2::new(self.w as f32, self.h as f32) / 2.
+    }
+
+    /// World point under position `pos` in the rect
+    fn unproject(&self, ray: Ray, pos: Vec2) -> Vec2 {
+        ray.orig + self.turn(ray).rotate((pos - Vec2::new(self.w as f32, self.h as f32) / 2.) / self.zoom)
+    }
+
+    /// Marks the pixels of the rect whose centres lie inside `corners`
+    fn fill_triangle(&self, corners: [Vec2; 3], mask: &mut [bool]) {
+        let [a, b, c] = corners;
+        let area: f32 = (b - a).perp_dot(c - a);
+        if area == 0. {
+            return;
+        }
+
+        let min: Vec2 = a.min(b).min(c).max(Vec2::ZERO);
+        let max: Vec2 = a.max(b).max(c).min(Vec2::new(self.w as f32, self.h as f32));
+        for py in min.y as usize..max.y.ceil() as usize {
+            for px in min.x as usize..max.x.ceil() as usize {
+                let p: Vec2 = Vec2::new(px as f32 + 0.5, py as f32 + 0.5);
+                // Same side of all three edges as the triangle winds
+                let inside = |u: Vec2, v: Vec2| (v - u).perp_dot(p - u) * area.signum() >= 0.;
+                if inside(a, b) && inside(b, c) && inside(c, a) {
+                    mask[py * self.w + px] = true;
+                }
+            }
+        }
+    }
+
+    fn line(&self, from: Vec2, to: Vec2, color: [u8; 4], out_img: &mut Framebuffer) {
+        let steps: usize = (to - from).abs().max_element().ceil() as usize + 1;
+        for i in 0..=steps {
+            self.plot(from.lerp(to, i as f32 / steps as f32), color, out_img);
+        }
+    }
+
+    /// Square `2 * radius + 1` pixels wide around `pos`
+    fn marker(&self, pos: Vec2, radius: i32, color: [u8; 4], out_img: &mut Framebuffer) {
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                self.plot(pos + Vec2::new(dx as f32, dy as f32), color, out_img);
+            }
+        }
+    }
+
+    /// Pixel at `pos` in the rect, clipped to it
+    fn plot(&self, pos: Vec2, color: [u8; 4], out_img: &mut Framebuffer) {
+        if pos.x >= 0. && pos.y >= 0. && (pos.x as usize) < self.w && (pos.y as usize) < self.h {
+            out_img.set(self.x + pos.x as usize, self.y + pos.y as usize, color);
+        }
+    }
+}
+
+/// Mean colour of a texture's visible pixels
+fn average(texture: &Framebuffer) -> [u8; 4] {
+    let (sum, count) = texture.data().iter()
+        .filter(|c| c[3] > 0)
+        .fold(([0u64; 3], 0u64), |(s, n), c| ([s[0] + c[0] as u64, s[1] + c[1] as u64, s[2] + c[2] as u64], n + 1));
+    if count == 0 {
+        return [0, 0, 0, 0];
+    }
+    [(sum[0] / count) as u8, (sum[1] / count) as u8, (sum[2] / count) as u8, 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::Viewport;
+    use crate::tests::{room, ROOM};
+
+    #[test]
+    fn minimap() {
+        let map: Map = room(ROOM, vec![('0', Framebuffer::from_pixels(2, 1, vec![[255, 0, 0, 255], [255, 100, 0, 255]]))]);
+        let view: Viewport = Viewport::new(16, 16);
+        let camera: Ray = Ray::new(Vec2::new(125., 125.), 0.);
+        let columns: Vec<(Intersection, f32)> = crate::render(&map, std::iter::empty(), camera, crate::Fog::NONE, &|| 0., 0., &view, &mut view.framebuffer());
+        let entities: [Entity; 2] = [Entity::new(Vec2::new(175., 175.), 'e', (10., 10.)), Entity::new(Vec2::new(75., 75.), 'e', (10., 10.))];
+
+        // The whole map, 10 pixels a cell, with the camera in the middle
+        let minimap: Minimap = Minimap::new(5, 5, 50, 50);
+        let mut out_img: Framebuffer = Framebuffer::new(60, 60);
+        minimap.draw(&map, entities.iter(), camera, &columns, None, &mut out_img);
+        assert_eq!((out_img.get(2, 2), out_img.get(7, 7)), ([0, 0, 0, 0], [255, 50, 0, 255]));
+        assert_eq!((out_img.get(30, 30), out_img.get(40, 40), out_img.get(20, 20)), (minimap.player, minimap.entity, minimap.entity));
+        // Floor lit up in front of the camera only
+        assert_eq!(out_img.get(20, 30), minimap.floor);
+        assert_ne!(out_img.get(40, 30), minimap.floor);
+
+        // Looking east, the west half stays unexplored along with the entity in it
+        let mut explored: Explored = Explored::new(&map);
+        explored.reveal(&map, camera, &columns);
+        assert!(explored.is_explored(IVec2::new(4, 2)) && !explored.is_explored(IVec2::new(1, 2)));
+        minimap.rotated().draw(&map, entities.iter(), camera, &columns, Some(&explored), &mut out_img);
+        assert_eq!((out_img.get(30, 7), out_img.get(30, 52)), ([255, 50, 0, 255], minimap.unexplored));
+        // Ahead and to the right, behind and to the left
+        assert_eq!((out_img.get(40, 20), out_img.get(20, 40)), (minimap.entity, minimap.unexplored));
+    }
+}
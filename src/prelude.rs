@@ -1,7 +1,17 @@
 pub use crate::*;
-pub use crate::map::{Map, Surface};
-pub use crate::util::{Ray, Intersection, IntersectionType, scrw, scrh, set_scrw_scrh, scr_topleft};
-pub use crate::entity::Entity;
+pub use crate::map::{Map, MapError, Surface, Sky, TextureRef, CellHeights};
+pub use crate::util::{Ray, Intersection, IntersectionType, Hit, Viewport, Camera, Scaling};
+pub use crate::entity::{Entity, SpriteSet};
+pub use crate::door::{Door, DoorState};
+pub use crate::wall::{Axis, ThinWall, Segment};
+pub use crate::light::Light;
+pub use crate::animation::{Animation, LoopMode};
+pub use crate::minimap::{Minimap, Explored};
+#[cfg(feature = "tiled")]
+pub use crate::tiled::TiledWarning;
+pub use crate::framebuffer::Framebuffer;
+#[cfg(feature = "macroquad")]
 pub use crate::item::Item;
 pub use glam;
+#[cfg(feature = "macroquad")]
 pub use macroquad;
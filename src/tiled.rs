@@ -0,0 +1,495 @@
+use crate::map::{Map, MapError, TextureRef};
+use crate::framebuffer::Framebuffer;
+use crate::entity::Entity;
+use crate::util::Ray;
+use glam::Vec2;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Horizontal, vertical, diagonal flip and hexagonal rotation bits of a gid
+const FLIP_FLAGS: u32 = 0xf0000000;
+/// Characters handed out to distinct tiles, in order
+const TILE_CHARS: &str = "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ!$%&*+-/:;<=>?@^_~";
+
+/// Part of a Tiled map that has no equivalent in `Map`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TiledWarning {
+    /// Left out, or placed without its flip flags
+    Tile { layer: String, x: usize, y: usize, gid: u32, reason: String },
+    /// Left out, or placed ignoring the unsupported attribute
+    Object { layer: String, id: u32, name: String, reason: String },
+    /// Whole layer left out
+    Layer { name: String, reason: String },
+}
+
+impl fmt::Display for TiledWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TiledWarning::Tile { layer, x, y, gid, reason } => write!(f, "layer {:?}, tile {} at ({}, {}): {}", layer, gid, x, y, reason),
+            TiledWarning::Object { layer, id, name, reason } => write!(f, "layer {:?}, object {} {:?}: {}", layer, id, name, reason),
+            TiledWarning::Layer { name, reason } => write!(f, "layer {:?}: {}", name, reason),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TiledMap {
+    orientation: String,
+    #[serde(default)]
+    infinite: bool,
+    width: usize,
+    height: usize,
+    tilewidth: u32,
+    tileheight: u32,
+    layers: Vec<Layer>,
+    #[serde(default)]
+    tilesets: Vec<TilesetRef>,
+}
+
+#[derive(Deserialize)]
+struct Layer {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default = "visible")]
+    visible: bool,
+    data: Option<LayerData>,
+    encoding: Option<String>,
+    compression: Option<String>,
+    #[serde(default)]
+    objects: Vec<Object>,
+    /// Children of group layers
+    #[serde(default)]
+    layers: Vec<Layer>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LayerData {
+    Gids(Vec<u32>),
+    /// Base64, possibly compressed
+    Encoded(serde::de::IgnoredAny),
+}
+
+#[derive(Deserialize)]
+struct Object {
+    id: u32,
+    #[serde(default)]
+    name: String,
+    /// Called `class` in Tiled 1.9
+    #[serde(default, rename = "type")]
+    kind: String,
+    #[serde(default)]
+    class: String,
+    gid: Option<u32>,
+    x: f32,
+    y: f32,
+    #[serde(default)]
+    width: f32,
+    #[serde(default)]
+    height: f32,
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "visible")]
+    visible: bool,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Deserialize)]
+struct TilesetRef {
+    firstgid: u32,
+    /// External tileset file, otherwise the tileset is embedded
+    source: Option<String>,
+    #[serde(flatten)]
+    tileset: Tileset,
+}
+
+#[derive(Deserialize, Default)]
+struct Tileset {
+    /// Atlas image, absent for image collection tilesets
+    image: Option<String>,
+    #[serde(default)]
+    columns: u32,
+    #[serde(default)]
+    tilecount: u32,
+    #[serde(default)]
+    tilewidth: u32,
+    #[serde(default)]
+    tileheight: u32,
+    #[serde(default)]
+    margin: u32,
+    #[serde(default)]
+    spacing: u32,
+    #[serde(default)]
+    tiles: Vec<Tile>,
+}
+
+#[derive(Deserialize)]
+struct Tile {
+    id: u32,
+    image: Option<String>,
+    #[serde(default)]
+    properties: Vec<Property>,
+}
+
+#[derive(Deserialize)]
+struct Property {
+    name: String,
+    value: serde_json::Value,
+}
+
+fn visible() -> bool {
+    true
+}
+
+fn property(properties: &[Property], name: &str) -> Option<f32> {
+    properties.iter()
+        .find(|p| p.name == name)
+        .and_then(|p| p.value.as_f64())
+        .map(|v| v as f32)
+}
+
+struct TilesetEntry {
+    firstgid: u32,
+    tileset: Tileset,
+    /// Directory its images are relative to
+    dir: PathBuf,
+    /// Same directory relative to the map file, for `Map::texture_path`
+    rel_dir: PathBuf,
+}
+
+struct Importer<'a> {
+    file: Option<&'a str>,
+    /// Sorted by firstgid
+    tilesets: Vec<TilesetEntry>,
+    images: HashMap<PathBuf, Framebuffer>,
+    /// gid -> tile character
+    tiles: HashMap<u32, char>,
+    textures: HashMap<char, Framebuffer>,
+    wall_heights: HashMap<char, f32>,
+    texture_paths: HashMap<TextureRef, String>,
+    player_start: Option<Ray>,
+    spawns: Vec<Entity>,
+    warnings: Vec<TiledWarning>,
+}
+
+impl<'a> Importer<'a> {
+    fn error(&self, message: String) -> MapError {
+        MapError::Import { file: self.file.map(String::from), message }
+    }
+
+    fn image(&mut self, path: PathBuf) -> Result<&Framebuffer, MapError> {
+        if !self.images.contains_key(&path) {
+            let img: Framebuffer = Framebuffer::load_png(&path)
+                .map_err(|e| self.error(format!("failed to load image {}: {}", path.display(), e)))?;
+            self.images.insert(path.clone(), img);
+        }
+
+        Ok(&self.images[&path])
+    }
+
+    /// Character for `gid` (without flip flags), `Ok(Err(reason))` if the tile can't be represented
+    fn tile(&mut self, gid: u32) -> Result<Result<char, String>, MapError> {
+        if let Some(&c) = self.tiles.get(&gid) {
+            return Ok(Ok(c));
+        }
+
+        let Some(index) = self.tilesets.iter().rposition(|t| t.firstgid <= gid) else {
+            return Ok(Err(String::from("gid doesn't belong to any tileset")));
+        };
+        let Some(c) = TILE_CHARS.chars().nth(self.tiles.len()) else {
+            return Ok(Err(format!("more than {} distinct tiles", TILE_CHARS.len())));
+        };
+
+        let TilesetEntry { firstgid, tileset, dir, rel_dir } = &self.tilesets[index];
+        let local: u32 = gid - firstgid;
+        let tile: Option<&Tile> = tileset.tiles.iter().find(|t| t.id == local);
+        let height: Option<f32> = tile.and_then(|t| property(&t.properties, "height"));
+
+        // (image, region of an atlas)
+        let source: (PathBuf, Option<(u32, u32, u32, u32)>) = if let Some(image) = tile.and_then(|t| t.image.as_ref()) {
+            // Only whole images can be referenced by path, atlas tiles stay unnamed
+            self.texture_paths.insert(TextureRef::Tile(c), rel_dir.join(image).to_string_lossy().into_owned());
+            (dir.join(image), None)
+        } else if let Some(image) = &tileset.image {
+            if tileset.tilecount > 0 && local >= tileset.tilecount {
+                return Ok(Err(String::from("tile id is past the end of its tileset")));
+            }
+
+            let (w, h) = (tileset.tilewidth, tileset.tileheight);
+            let columns: u32 = tileset.columns.max(1);
+            let x: u32 = tileset.margin + (local % columns) * (w + tileset.spacing);
+            let y: u32 = tileset.margin + (local / columns) * (h + tileset.spacing);
+            (dir.join(image), Some((x, y, w, h)))
+        } else {
+            return Ok(Err(String::from("tile has no image")));
+        };
+
+        let image: &Framebuffer = self.image(source.0)?;
+        let texture: Framebuffer = match source.1 {
+            None => image.clone(),
+            Some((x, y, w, h)) => {
+                if (x + w) as usize > image.width() || (y + h) as usize > image.height() {
+                    return Ok(Err(String::from("tile lies outside its tileset image")));
+                }
+                image.crop(x as usize, y as usize, w as usize, h as usize)
+            }
+        };
+
+        self.tiles.insert(gid, c);
+        self.textures.insert(c, texture);
+        if let Some(height) = height {
+            self.wall_heights.insert(c, height);
+        }
+        Ok(Ok(c))
+    }
+
+    fn tile_layer(&mut self, layer: &Layer, w: usize, h: usize, grid: &mut [char]) -> Result<(), MapError> {
+        let gids: &Vec<u32> = match (&layer.data, &layer.encoding, &layer.compression) {
+            (Some(LayerData::Gids(gids)), _, None) => gids,
+            (Some(_), encoding, compression) => return Err(self.error(format!(
+                "layer {:?} uses {} encoding{}, export with CSV layer format",
+                layer.name,
+                encoding.as_deref().unwrap_or("an unknown"),
+                compression.as_ref().map_or(String::new(), |c| format!(" and {} compression", c))
+            ))),
+            (None, ..) => return Err(self.error(format!("layer {:?} has no data, infinite maps aren't supported", layer.name))),
+        };
+        if gids.len() != w * h {
+            return Err(self.error(format!("layer {:?} has {} tiles, expected {}", layer.name, gids.len(), w * h)));
+        }
+
+        for (i, &raw) in gids.iter().enumerate() {
+            if raw == 0 {
+                continue;
+            }
+
+            let gid: u32 = raw & !FLIP_FLAGS;
+            let warn = |reason: String| TiledWarning::Tile { layer: layer.name.clone(), x: i % w, y: i / w, gid, reason };
+            if raw & FLIP_FLAGS != 0 {
+                self.warnings.push(warn(String::from("flipped or rotated tiles are placed unflipped")));
+            }
+
+            match self.tile(gid)? {
+                Ok(c) => grid[i] = c,
+                Err(reason) => self.warnings.push(warn(reason)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn object_layer(&mut self, layer: &Layer) -> Result<(), MapError> {
+        for obj in layer.objects.iter().filter(|o| o.visible) {
+            let warn = |reason: &str| TiledWarning::Object { layer: layer.name.clone(), id: obj.id, name: obj.name.clone(), reason: String::from(reason) };
+
+            if [&obj.name, &obj.kind, &obj.class].iter().any(|s| s.eq_ignore_ascii_case("player")) {
+                // Centre of its rect, tile objects are anchored at their bottom left corner and others at their top left
+                let dy: f32 = if obj.gid.is_some() { -obj.height / 2. } else { obj.height / 2. };
+                let pos: Vec2 = Vec2::new(obj.x + obj.width / 2., obj.y + dy);
+                let angle: f32 = property(&obj.properties, "angle").unwrap_or(obj.rotation.to_radians());
+                self.player_start = Some(Ray::new(pos, angle));
+                continue;
+            }
+
+            let Some(raw) = obj.gid else {
+                self.warnings.push(warn("only tile objects and a `player` object are supported"));
+                continue;
+            };
+            if raw & FLIP_FLAGS != 0 {
+                self.warnings.push(warn("flipped tile objects are placed unflipped"));
+            }
+
+            let angle: f32 = property(&obj.properties, "angle").unwrap_or(obj.rotation.to_radians());
+            let z: f32 = property(&obj.properties, "z").unwrap_or(0.);
+            match self.tile(raw & !FLIP_FLAGS)? {
+                // Tile objects are anchored at their bottom left corner
+                Ok(c) => self.spawns.push(Entity::new(
+                    Vec2::new(obj.x + obj.width / 2., obj.y - obj.height / 2.),
+                    c,
+                    (obj.width, property(&obj.properties, "height").unwrap_or(obj.height))
+                ).with_angle(angle).with_z(z)),
+                Err(reason) => self.warnings.push(warn(&reason)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn layers(&mut self, layers: &[Layer], w: usize, h: usize, grid: &mut [char]) -> Result<(), MapError> {
+        for layer in layers.iter().filter(|l| l.visible) {
+            match layer.kind.as_str() {
+                "tilelayer" => self.tile_layer(layer, w, h, grid)?,
+                "objectgroup" => self.object_layer(layer)?,
+                "group" => self.layers(&layer.layers, w, h, grid)?,
+                kind => self.warnings.push(TiledWarning::Layer { name: layer.name.clone(), reason: format!("{} layers aren't supported", kind) }),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Map {
+    /// Imports an orthogonal map exported from Tiled as JSON, with CSV layer data.
+    ///
+    /// Visible tile layers are merged into the wall layout, each distinct tile gets its own character and texture.
+    /// A numeric `height` tile property becomes the wall height. Tile objects become `spawns`, sized by the object
+    /// (a `height` property overrides the sprite height, a `z` property raises it off the floor) and facing their `angle` property
+    /// or rotation. An object named or typed `player` sets `player_start`, facing the same way. Everything else that can't be represented is returned as warnings.
+    pub fn from_tiled(path: &str) -> Result<(Self, Vec<TiledWarning>), MapError> {
+        let src: String = fs::read_to_string(path).map_err(|source| MapError::Io { path: path.to_string(), source })?;
+        let dir: &Path = Path::new(path).parent().unwrap_or(Path::new(""));
+        Map::parse_tiled(&src, Some(path), dir)
+    }
+
+    /// Tiled JSON source with tileset and image paths resolved relative to `dir`
+    pub fn from_tiled_json(src: &str, dir: &str) -> Result<(Self, Vec<TiledWarning>), MapError> {
+        Map::parse_tiled(src, None, Path::new(dir))
+    }
+
+    fn parse_tiled(src: &str, file: Option<&str>, dir: &Path) -> Result<(Self, Vec<TiledWarning>), MapError> {
+        let error = |message: String| MapError::Import { file: file.map(String::from), message };
+
+        let tiled: TiledMap = serde_json::from_str(src).map_err(|e| error(e.to_string()))?;
+        if tiled.orientation != "orthogonal" {
+            return Err(error(format!("{} maps aren't supported", tiled.orientation)));
+        }
+        if tiled.infinite {
+            return Err(error(String::from("infinite maps aren't supported")));
+        }
+        if tiled.tilewidth != tiled.tileheight {
+            return Err(error(format!("tiles must be square, found {}x{}", tiled.tilewidth, tiled.tileheight)));
+        }
+
+        let mut tilesets: Vec<TilesetEntry> = Vec::new();
+        for tileset in tiled.tilesets {
+            match tileset.source {
+                Some(source) => {
+                    let path: PathBuf = dir.join(&source);
+                    let ext: Option<&str> = path.extension().and_then(|e| e.to_str());
+                    if !matches!(ext, Some("json" | "tsj")) {
+                        return Err(error(format!("external tileset {} must be exported as JSON", source)));
+                    }
+
+                    let src: String = fs::read_to_string(&path).map_err(|e| error(format!("{}: {}", path.display(), e)))?;
+                    let external: Tileset = serde_json::from_str(&src).map_err(|e| error(format!("{}: {}", path.display(), e)))?;
+                    tilesets.push(TilesetEntry {
+                        firstgid: tileset.firstgid,
+                        tileset: external,
+                        dir: path.parent().map_or(PathBuf::new(), Path::to_path_buf),
+                        rel_dir: Path::new(&source).parent().map_or(PathBuf::new(), Path::to_path_buf),
+                    });
+                }
+                None => tilesets.push(TilesetEntry {
+                    firstgid: tileset.firstgid,
+                    tileset: tileset.tileset,
+                    dir: dir.to_path_buf(),
+                    rel_dir: PathBuf::new(),
+                }),
+            }
+        }
+        tilesets.sort_by_key(|t| t.firstgid);
+
+        let mut importer: Importer = Importer {
+            file,
+            tilesets,
+            images: HashMap::new(),
+            tiles: HashMap::new(),
+            textures: HashMap::new(),
+            wall_heights: HashMap::new(),
+            texture_paths: HashMap::new(),
+            player_start: None,
+            spawns: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        let (w, h) = (tiled.width, tiled.height);
+        let mut grid: Vec<char> = vec!['.'; w * h];
+        importer.layers(&tiled.layers, w, h, &mut grid)?;
+
+        let layout: String = grid.chunks(w.max(1))
+            .map(|row| row.iter().collect::<String>() + "\n")
+            .collect();
+        let mut map: Map = Map::parse(&layout, file, 1, importer.textures, HashMap::new(), HashMap::new())?;
+        map.tsize = tiled.tilewidth as f32;
+        map.wall_heights = importer.wall_heights;
+        map.texture_paths = importer.texture_paths;
+        map.player_start = importer.player_start;
+        map.spawns = importer.spawns;
+
+        Ok((map, importer.warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = r#"{
+        "orientation": "orthogonal", "infinite": false,
+        "width": 3, "height": 2, "tilewidth": 40, "tileheight": 40,
+        "tilesets": [
+            { "firstgid": 1, "tiles": [
+                { "id": 0, "image": "wall.png", "properties": [{ "name": "height", "type": "float", "value": 2 }] },
+                { "id": 1, "image": "shrek.png" }
+            ] },
+            { "firstgid": 10, "image": "wall.png", "columns": 2, "tilecount": 4, "tilewidth": 25, "tileheight": 25 }
+        ],
+        "layers": [
+            { "type": "tilelayer", "name": "walls", "width": 3, "height": 2, "data": [1, 0, 13, 2147483649, 0, 0] },
+            { "type": "objectgroup", "name": "things", "objects": [
+                { "id": 1, "name": "shrek", "gid": 2, "x": 40, "y": 80, "width": 20, "height": 30, "rotation": 180 },
+                { "id": 2, "name": "player", "x": 60, "y": 60, "point": true, "rotation": 90 },
+                { "id": 3, "name": "trigger", "x": 0, "y": 0, "width": 10, "height": 10 }
+            ] },
+            { "type": "imagelayer", "name": "backdrop" }
+        ]
+    }"#;
+
+    #[test]
+    fn import() {
+        let (map, warnings) = Map::from_tiled_json(MAP, "examples/res").unwrap();
+        assert_eq!((map.w, map.h, map.tsize), (3., 2., 40.));
+        assert_eq!((map.at(0, 0), map.at(1, 0), map.at(2, 0), map.at(0, 1)), ('0', '.', '1', '0'));
+        assert_eq!(map.wall_heights[&'0'], 2.);
+        // Atlas tile 3 is the bottom right quarter of wall.png
+        assert_eq!(map.textures[&'1'].width(), 25);
+        assert_eq!(map.texture_paths[&TextureRef::Tile('0')], "wall.png");
+
+        assert_eq!(map.spawns.len(), 1);
+        assert_eq!(map.spawns[0].pos, Vec2::new(50., 65.));
+        assert_eq!(map.spawns[0].texture, '2');
+        assert!((map.spawns[0].angle - std::f32::consts::PI).abs() < 0.001);
+        let start: Ray = map.player_start.unwrap();
+        assert_eq!(start.orig, Vec2::new(60., 60.));
+        assert!((start.angle - std::f32::consts::PI / 2.).abs() < 0.001);
+
+        assert_eq!(warnings.len(), 3);
+        assert!(matches!(&warnings[0], TiledWarning::Tile { x: 0, y: 1, gid: 1, .. }));
+        assert!(matches!(&warnings[1], TiledWarning::Object { id: 3, .. }));
+        assert!(matches!(&warnings[2], TiledWarning::Layer { .. }));
+    }
+
+    #[test]
+    fn tile_object_player() {
+        let tile: String = MAP.replace(r#""name": "player", "x": 60, "y": 60, "point": true"#, r#""name": "player", "gid": 2, "x": 60, "y": 80, "width": 20, "height": 20"#);
+        let (map, _) = Map::from_tiled_json(&tile, "examples/res").unwrap();
+        assert_eq!(map.player_start.unwrap().orig, Vec2::new(70., 70.));
+        assert_eq!(map.spawns.len(), 1);
+    }
+
+    #[test]
+    fn unsupported() {
+        let iso: String = MAP.replace("orthogonal", "isometric");
+        assert!(matches!(Map::from_tiled_json(&iso, "examples/res"), Err(MapError::Import { .. })));
+
+        let base64: String = MAP.replace("\"data\": [1, 0, 13, 2147483649, 0, 0]", "\"encoding\": \"base64\", \"data\": \"AQAAAA==\"");
+        assert!(matches!(Map::from_tiled_json(&base64, "examples/res"), Err(MapError::Import { .. })));
+    }
+}
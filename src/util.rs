@@ -1,11 +1,11 @@
 use crate::util;
 use crate::map::Map;
+use crate::framebuffer::Framebuffer;
+#[cfg(feature = "macroquad")]
 use macroquad::prelude as mq;
-use glam::{Vec2, IVec2};
+use glam::{Vec2, Vec3, IVec2};
 use std::f32::consts::PI;
 
-static mut SCRDIM: IVec2 = IVec2::new(0, 0);
-
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Direction {
     North,
@@ -16,8 +16,15 @@ pub enum Direction {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum IntersectionType {
-    Wall { gpos: IVec2, face: Direction },
-    Entity { index: usize, col: f32 }
+    /// `col` is the horizontal texture coordinate in [0, 1)
+    Wall { gpos: IVec2, face: Direction, col: f32 },
+    /// Index into `Map::segments`, `normal` faces the right of the segment regardless of the side hit
+    Segment { index: usize, col: f32, normal: Vec2 },
+    Entity { index: usize, col: f32 },
+    /// Floor of the cell a `hit_scan` shot stopped on before reaching a wall
+    Floor { gpos: IVec2 },
+    /// Ceiling of the cell a `hit_scan` shot stopped on before reaching a wall
+    Ceiling { gpos: IVec2 },
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -29,6 +36,46 @@ pub struct Intersection {
     pub fisheye_distance: f32,
 }
 
+/// Intersection of a shot placed in 3D, from `hit_scan`
+#[derive(Debug, Copy, Clone)]
+pub struct Hit {
+    pub ins: Intersection,
+    /// World position of the hit, `z` is its elevation
+    pub pos: Vec3,
+    /// Pixel of the sprite texture hit, `None` for walls
+    pub texel: Option<(usize, usize)>,
+}
+
+/// Size of a rendered view and where it sits on screen
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Viewport {
+    /// Screen position of the top left corner
+    pub x: f32,
+    pub y: f32,
+    /// Render resolution, the size of the framebuffer
+    pub w: i32,
+    pub h: i32,
+    /// Screen pixels per rendered pixel, the image is upscaled with nearest sampling
+    pub scale: f32,
+    pub camera: Camera,
+}
+
+/// How `Viewport::fit` stretches a fixed resolution over the screen
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Scaling {
+    /// As large as fits, pixels may come out unevenly sized
+    Nearest,
+    /// Whole multiples only, every pixel the same size at the cost of a wider border
+    Integer,
+}
+
+/// Projection used to turn world space into screen space
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Camera {
+    /// Horizontal field of view in radians, vertical fov follows from the viewport aspect ratio
+    pub hfov: f32,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Ray {
     pub orig: Vec2,
@@ -63,6 +110,159 @@ impl Intersection {
     }
 }
 
+impl Camera {
+    pub fn new(hfov: f32) -> Self {
+        Self { hfov }
+    }
+
+    /// Keeps `vfov` fixed and widens the horizontal fov with the aspect ratio (w / h)
+    pub fn from_vfov(vfov: f32, aspect: f32) -> Self {
+        Self::new(2. * f32::atan(f32::tan(vfov / 2.) * aspect))
+    }
+
+    /// Narrower fov magnified by `factor`, for scopes and zooming
+    pub fn zoomed(&self, factor: f32) -> Self {
+        Self::new(2. * f32::atan(f32::tan(self.hfov / 2.) / factor))
+    }
+
+    /// Distance in pixels from the eye to the projection plane of a `w` pixel wide view
+    pub fn proj_dist(&self, w: i32) -> f32 {
+        (w as f32 / 2.) / f32::tan(self.hfov / 2.)
+    }
+
+    pub fn vfov(&self, w: i32, h: i32) -> f32 {
+        2. * f32::atan((h as f32 / 2.) / self.proj_dist(w))
+    }
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self::new(PI / 3.)
+    }
+}
+
+impl Viewport {
+    /// Placed at the top left of the screen
+    pub fn new(w: i32, h: i32) -> Self {
+        Self { x: 0., y: 0., w, h, scale: 1., camera: Camera::default() }
+    }
+
+    /// Fixed `w` by `h` resolution scaled up to fit a `screen_w` by `screen_h` area, centered with its aspect ratio kept
+    pub fn fit(w: i32, h: i32, screen_w: f32, screen_h: f32, scaling: Scaling) -> Self {
+        let scale: f32 = f32::min(screen_w / w as f32, screen_h / h as f32);
+        let scale: f32 = match scaling {
+            Scaling::Nearest => scale,
+            Scaling::Integer => scale.floor().max(1.),
+        };
+        Self::new(w, h).with_scale(scale).at((screen_w - w as f32 * scale) / 2., (screen_h - h as f32 * scale) / 2.)
+    }
+
+    /// `fit` to the macroquad window
+    #[cfg(feature = "macroquad")]
+    pub fn fit_window(w: i32, h: i32, scaling: Scaling) -> Self {
+        Self::fit(w, h, mq::screen_width(), mq::screen_height(), scaling)
+    }
+
+    /// Renders at `factor` of the resolution, scaled up to cover the same screen area
+    pub fn downscaled(mut self, factor: f32) -> Self {
+        let (screen_w, screen_h) = self.screen_size();
+        self.w = ((self.w as f32 * factor).round() as i32).max(1);
+        self.h = ((self.h as f32 * factor).round() as i32).max(1);
+        self.scale = screen_w / self.w as f32;
+        // Rounding may leave the height a little off the original area
+        self.y += (screen_h - self.h as f32 * self.scale) / 2.;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_camera(mut self, camera: Camera) -> Self {
+        self.camera = camera;
+        self
+    }
+
+    pub fn at(mut self, x: f32, y: f32) -> Self {
+        self.x = x;
+        self.y = y;
+        self
+    }
+
+    /// Centered in the macroquad window
+    #[cfg(feature = "macroquad")]
+    pub fn centered(w: i32, h: i32) -> Self {
+        Self::new(w, h).at(
+            mq::screen_width() / 2. - w as f32 / 2.,
+            mq::screen_height() / 2. - h as f32 / 2.
+        )
+    }
+
+    pub fn topleft(&self) -> (f32, f32) {
+        (self.x, self.y)
+    }
+
+    /// Size of the upscaled image on screen
+    pub fn screen_size(&self) -> (f32, f32) {
+        (self.w as f32 * self.scale, self.h as f32 * self.scale)
+    }
+
+    /// Screen position of a point in rendered pixels
+    pub fn to_screen(&self, pos: Vec2) -> Vec2 {
+        Vec2::new(self.x, self.y) + pos * self.scale
+    }
+
+    /// Draws the texture of a rendered view over its screen area
+    #[cfg(feature = "macroquad")]
+    pub fn draw(&self, texture: &mq::Texture2D) {
+        let (w, h) = self.screen_size();
+        mq::draw_texture_ex(texture, self.x, self.y, mq::WHITE, mq::DrawTextureParams {
+            dest_size: Some(mq::Vec2::new(w, h)),
+            ..Default::default()
+        });
+    }
+
+    /// Rendered pixel position under a screen position, `None` outside the view
+    pub fn to_view(&self, screen: Vec2) -> Option<Vec2> {
+        let pos: Vec2 = (screen - Vec2::new(self.x, self.y)) / self.scale;
+        (pos.x >= 0. && pos.y >= 0. && pos.x < self.w as f32 && pos.y < self.h as f32).then_some(pos)
+    }
+
+    /// Ray through the pixel under a screen position, such as the mouse, seen from `cam`.
+    /// `vangle` is the pixel's angle below the horizon along the column's ray, which is longer off centre.
+    pub fn pick_ray(&self, cam: Ray, screen: Vec2) -> Option<Ray> {
+        let pos: Vec2 = self.to_view(screen)?;
+        let column_angle: f32 = self.column_angle(pos.x as i32);
+        let mut ray: Ray = Ray::new(cam.orig, restrict_angle(cam.angle + column_angle));
+        ray.vangle = f32::atan((pos.y.floor() + 0.5 - self.horizon(cam.vangle)) * f32::cos(column_angle) / self.proj_dist());
+        Some(ray)
+    }
+
+    pub fn proj_dist(&self) -> f32 {
+        self.camera.proj_dist(self.w)
+    }
+
+    pub fn vfov(&self) -> f32 {
+        self.camera.vfov(self.w, self.h)
+    }
+
+    /// Angle of screen column `x` relative to the view direction
+    pub fn column_angle(&self, x: i32) -> f32 {
+        f32::atan((x as f32 + 0.5 - self.w as f32 / 2.) / self.proj_dist())
+    }
+
+    /// Screen row of the horizon when looking `vangle` down
+    pub fn horizon(&self, vangle: f32) -> f32 {
+        self.h as f32 / 2. - f32::tan(vangle) * self.proj_dist()
+    }
+
+    /// Blank framebuffer with the dimensions of the viewport
+    pub fn framebuffer(&self) -> Framebuffer {
+        Framebuffer::new(self.w as usize, self.h as usize)
+    }
+}
+
 impl Ray {
     pub fn new(orig: Vec2, angle: f32) -> Self {
         Self { orig, angle, vangle: 0. }
@@ -99,6 +299,7 @@ pub fn move_towards(pos: Vec2, target: Vec2, speed: f32) -> Vec2 {
     Ray::new(pos, util::restrict_angle(angle)).along(speed)
 }
 
+#[cfg(feature = "macroquad")]
 pub fn fps_camera_controls(map: &Map, cam: &mut Ray, speed: f32) {
     if mq::is_key_down(mq::KeyCode::W) {
         cam.orig = map.move_collidable(cam.orig, Ray::new(cam.orig, cam.angle).along(speed));
@@ -117,32 +318,48 @@ pub fn fps_camera_controls(map: &Map, cam: &mut Ray, speed: f32) {
     }
 }
 
+#[cfg(feature = "macroquad")]
 pub fn fps_camera_rotation(cam: &mut Ray, prev_mouse_pos: &mut (f32, f32), sensitivity: f32) {
     let mpos: (f32, f32) = mq::mouse_position();
     cam.angle += sensitivity * (mpos.0 - prev_mouse_pos.0) / 200.;
     cam.vangle += sensitivity * (mpos.1 - prev_mouse_pos.1) / 200.;
     cam.angle = restrict_angle(cam.angle);
-    cam.vangle = cam.vangle.max(-1.).min(1.);
+    cam.vangle = cam.vangle.clamp(-1., 1.);
     *prev_mouse_pos = mpos;
 }
 
-pub fn scrw() -> i32 {
-    unsafe { SCRDIM.x }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn scrh() -> i32 {
-    unsafe { SCRDIM.y }
-}
+    #[test]
+    fn projection() {
+        let view: Viewport = Viewport::new(200, 100).with_camera(Camera::new(PI / 2.));
+        assert!((view.proj_dist() - 100.).abs() < 0.001);
+        assert!((view.vfov() - 2. * f32::atan(0.5)).abs() < 0.001);
+        assert!((view.horizon(0.) - 50.).abs() < 0.001);
 
-pub fn set_scrw_scrh(w: i32, h: i32) {
-    unsafe {
-        SCRDIM.x = w;
-        SCRDIM.y = h;
+        let zoomed: Camera = view.camera.zoomed(2.);
+        assert!((zoomed.proj_dist(200) - 200.).abs() < 0.001);
+        assert!((Camera::from_vfov(view.vfov(), 2.).hfov - PI / 2.).abs() < 0.001);
     }
-}
 
-pub fn scr_topleft() -> (f32, f32) {
-    let top_x: f32 = if scrw() == mq::screen_width() as i32 { 0. } else { mq::screen_width() / 2. - scrw() as f32 / 2. };
-    let top_y: f32 = if scrh() == mq::screen_height() as i32 { 0. } else { mq::screen_height() / 2. - scrh() as f32 / 2. };
-    (top_x, top_y)
+    #[test]
+    fn scaling() {
+        let view: Viewport = Viewport::fit(320, 200, 1000., 800., Scaling::Integer);
+        assert_eq!((view.scale, view.topleft()), (3., (20., 100.)));
+        let view: Viewport = Viewport::fit(320, 200, 1000., 800., Scaling::Nearest);
+        assert_eq!((view.scale, view.screen_size()), (3.125, (1000., 625.)));
+
+        let view: Viewport = Viewport::new(800, 600).at(10., 0.).downscaled(0.5);
+        assert_eq!((view.w, view.h, view.scale, view.screen_size()), (400, 300, 2., (800., 600.)));
+        assert_eq!(view.to_view(Vec2::new(15., 7.)), Some(Vec2::new(2.5, 3.5)));
+        assert_eq!(view.to_view(Vec2::new(5., 7.)), None);
+        assert_eq!(view.to_screen(Vec2::new(2.5, 3.5)), Vec2::new(15., 7.));
+
+        // The center of the screen picks the camera's own ray
+        let cam: Ray = Ray::new(Vec2::new(50., 50.), 1.);
+        let ray: Ray = view.pick_ray(cam, Vec2::new(410., 300.)).unwrap();
+        assert!((ray.angle - 1.).abs() < 0.01 && ray.vangle.abs() < 0.01);
+    }
 }
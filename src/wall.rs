@@ -0,0 +1,102 @@
+use crate::util::Ray;
+use glam::{Vec2, IVec2};
+
+/// Axis a thin wall runs along
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    /// Plane of constant y
+    X,
+    /// Plane of constant x
+    Y,
+}
+
+/// Wall plane inside a cell instead of on its edges, textured on both sides
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ThinWall {
+    pub axis: Axis,
+    /// Position of the plane across the cell, 0.5 is centered
+    pub offset: f32,
+}
+
+impl ThinWall {
+    pub fn new(axis: Axis, offset: f32) -> Self {
+        Self { axis, offset }
+    }
+
+    pub fn centered(axis: Axis) -> Self {
+        Self::new(axis, 0.5)
+    }
+}
+
+/// Wall between two arbitrary points in world space, textured on both sides with `tile`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub a: Vec2,
+    pub b: Vec2,
+    pub tile: char,
+}
+
+impl Segment {
+    pub fn new(a: Vec2, b: Vec2, tile: char) -> Self {
+        Self { a, b, tile }
+    }
+
+    /// Diagonal across cell `gpos`, from top left to bottom right when `falling`, else bottom left to top right
+    pub fn diagonal(gpos: IVec2, tsize: f32, falling: bool, tile: char) -> Self {
+        let corner: Vec2 = gpos.as_vec2() * tsize;
+        if falling {
+            Self::new(corner, corner + Vec2::splat(tsize), tile)
+        } else {
+            Self::new(corner + Vec2::new(0., tsize), corner + Vec2::new(tsize, 0.), tile)
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.a.distance(self.b)
+    }
+
+    /// Unit normal on the right of `a` to `b` in screen coordinates, used for shading
+    pub fn normal(&self) -> Vec2 {
+        (self.b - self.a).perp().normalize_or_zero()
+    }
+
+    /// (distance along the ray, distance from `a` along the segment)
+    pub fn intersect(&self, ray: Ray) -> Option<(f32, f32)> {
+        let dir: Vec2 = ray.dir();
+        let edge: Vec2 = self.b - self.a;
+        let denom: f32 = dir.perp_dot(edge);
+        if denom.abs() < 0.00001 {
+            return None;
+        }
+
+        let to_a: Vec2 = self.a - ray.orig;
+        let t: f32 = to_a.perp_dot(edge) / denom;
+        let s: f32 = to_a.perp_dot(dir) / denom;
+        if t > 0. && (0f32..=1f32).contains(&s) {
+            Some((t, s * edge.length()))
+        } else {
+            None
+        }
+    }
+
+    pub fn closest_point(&self, pos: Vec2) -> Vec2 {
+        let edge: Vec2 = self.b - self.a;
+        let s: f32 = ((pos - self.a).dot(edge) / edge.length_squared()).clamp(0., 1.);
+        self.a + edge * s
+    }
+
+    /// Whether the straight path from `from` to `to` passes through the segment
+    pub fn crosses(&self, from: Vec2, to: Vec2) -> bool {
+        let edge: Vec2 = self.b - self.a;
+        let step: Vec2 = to - from;
+        let denom: f32 = step.perp_dot(edge);
+        if denom.abs() < 0.00001 {
+            return false;
+        }
+
+        let to_a: Vec2 = self.a - from;
+        let t: f32 = to_a.perp_dot(edge) / denom;
+        let s: f32 = to_a.perp_dot(step) / denom;
+        (0f32..=1f32).contains(&t) && (0f32..=1f32).contains(&s)
+    }
+}